@@ -1,4 +1,4 @@
-use std::{env, io, path::PathBuf, time::Duration};
+use std::{collections::HashMap, env, io, path::PathBuf, time::Duration};
 
 use async_nats::ConnectOptions;
 use config::{Config, ConfigError, Environment, File};
@@ -70,6 +70,35 @@ pub struct IronhiveConfig {
     /// The initial capacity of the read buffer
     #[serde(skip_serializing_if = "Option::is_none")]
     read_buffer_capacity: Option<u16>,
+    /// The subject results of scheduled tasks are published to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sched_task_subject: Option<String>,
+    /// Directories file requests are limited to, all paths are allowed if empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    allowed_paths: Vec<PathBuf>,
+    /// Targets tunnels may connect to as `host:port`, `*` matches any host or port, only
+    /// localhost if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tunnel_targets: Option<Vec<String>>,
+    /// Requests arrive on `<subject_prefix>.<agent_id>` and `<subject_prefix>.<agent_id>.<func>`
+    /// when set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject_prefix: Option<String>,
+    /// Groups, like a site or a role, the agent also takes requests for on `group.<group>`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    groups: Vec<String>,
+    /// How many requests are handled at once, unlimited if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_concurrent: Option<usize>,
+    /// How many requests of a function, like `runscript`, are handled at once
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    max_concurrent_per_func: HashMap<String, usize>,
+    /// How many requests may wait for their turn before the agent replies busy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_queued: Option<usize>,
+    /// How long requests in flight get to finish on shutdown before their jobs are cancelled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shutdown_timeout: Option<Duration>,
 }
 
 pub fn proj_dirs() -> Result<ProjectDirs, ConfigError> {
@@ -120,7 +149,35 @@ impl IronhiveConfig {
     pub async fn agent_and_options(
         mut self,
     ) -> Result<(Agent, ConnectOptions), ironhive_core::Error> {
-        let agent = Agent::with_servers(self.agent_id.clone(), self.addrs.drain(..));
+        let mut agent = Agent::with_servers(self.agent_id.clone(), self.addrs.drain(..));
+
+        if let Ok(proj_dirs) = proj_dirs() {
+            agent.data_dir = proj_dirs.data_dir().into();
+        }
+
+        if let Some(sched_task_subject) = self.sched_task_subject.take() {
+            agent.sched_task_subject = sched_task_subject;
+        }
+
+        agent.allowed_paths = std::mem::take(&mut self.allowed_paths);
+
+        if let Some(tunnel_targets) = self.tunnel_targets.take() {
+            agent.tunnel_targets = tunnel_targets;
+        }
+
+        agent.subject_prefix = self.subject_prefix.take();
+
+        agent.groups = std::mem::take(&mut self.groups);
+
+        agent.limits.max_concurrent = self.max_concurrent;
+        agent.limits.max_concurrent_per_func = std::mem::take(&mut self.max_concurrent_per_func);
+        if let Some(max_queued) = self.max_queued {
+            agent.limits.max_queued = max_queued;
+        }
+
+        if let Some(shutdown_timeout) = self.shutdown_timeout {
+            agent.shutdown_timeout = shutdown_timeout;
+        }
 
         let options = self.connect_options().await?;
 
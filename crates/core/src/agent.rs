@@ -1,9 +1,16 @@
-use std::{ops::Deref, time::Duration};
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
 use crate::cmd::CmdExe;
 use crate::error::Error;
+use crate::limits::Limits;
+use crate::temp_file::{check_own, create_new};
 use async_nats::ToServerAddrs;
-use shared::ProcessMsg;
+use sha2::{Digest, Sha256};
+use shared::{FileEntry, FileKind, IronhiveRequest, ProcessMsg, ScriptMode};
 use sysinfo::{CpuExt, DiskExt, Pid, PidExt, ProcessExt, SystemExt, UserExt};
 
 // 定义Agent结构体
@@ -12,6 +19,22 @@ pub struct Agent {
     pub agent_id: String,
     pub nats_servers: Vec<async_nats::ServerAddr>,
     pub system: sysinfo::System,
+    /// Directory for the agent's persistent data, such as scheduled tasks.
+    pub data_dir: PathBuf,
+    /// Subject the results of scheduled tasks are published to.
+    pub sched_task_subject: String,
+    /// Directories the file requests are limited to, nothing is off limits if empty.
+    pub allowed_paths: Vec<PathBuf>,
+    /// Targets tunnels may connect to as `host:port`, either may be `*` to match any.
+    pub tunnel_targets: Vec<String>,
+    /// Prefix of the agent's subjects, see [`Agent::subject`].
+    pub subject_prefix: Option<String>,
+    /// Groups the agent takes requests for next to its own, see [`shared::group_subject`].
+    pub groups: Vec<String>,
+    /// How many requests are handled at once.
+    pub limits: Limits,
+    /// How long requests in flight get to finish on shutdown before their jobs are cancelled.
+    pub shutdown_timeout: Duration,
     version: String,
     host_name: String,
 }
@@ -25,6 +48,14 @@ impl Default for Agent {
             host_name: system.host_name().unwrap_or_default(),
             system,
             nats_servers: Default::default(),
+            data_dir: std::env::temp_dir().join("ironhive"),
+            sched_task_subject: "agent-schedtask".into(),
+            allowed_paths: Default::default(),
+            tunnel_targets: vec!["localhost:*".into(), "127.0.0.1:*".into(), "[::1]:*".into()],
+            subject_prefix: Default::default(),
+            groups: Default::default(),
+            limits: Default::default(),
+            shutdown_timeout: Duration::from_secs(30),
         }
     }
 }
@@ -66,6 +97,14 @@ impl Agent {
         }
     }
 
+    /// The subject requests of any function arrive on, `{subject_prefix}.{agent_id}` or just
+    /// the agent id without a prefix.
+    ///
+    /// The subjects of each function, shell sessions and tunnels are below it.
+    pub fn subject(&self) -> String {
+        shared::agent_subject(self.subject_prefix.as_deref(), &self.agent_id)
+    }
+
     pub fn version(&self) -> &str {
         &self.version
     }
@@ -146,6 +185,15 @@ impl Agent {
             .collect()
     }
 
+    pub fn sched_tasks_path(&self) -> PathBuf {
+        self.data_dir.join("sched_tasks.json")
+    }
+
+    /// Where detached commands write their output, only the agent may access it.
+    pub fn detached_log_dir(&self) -> PathBuf {
+        self.data_dir.join("detached")
+    }
+
     pub fn kill_proc(&self, pid: Pid) -> Result<(), Error> {
         let process: &sysinfo::Process = self
             .system
@@ -157,6 +205,415 @@ impl Agent {
 
         Ok(())
     }
+
+    /// Checks `path` is within `allowed_paths` once symlinks and `..` are resolved.
+    pub fn check_path_allowed(&self, path: &Path) -> Result<(), Error> {
+        if !path.is_absolute() {
+            return Err(Error::NotAbsolutePath(path.to_path_buf()));
+        }
+        if self.allowed_paths.is_empty() {
+            return Ok(());
+        }
+
+        let resolved = resolve_path(path)?;
+        if self
+            .allowed_paths
+            .iter()
+            .filter_map(|allowed| resolve_path(allowed).ok())
+            .any(|allowed| resolved.starts_with(allowed))
+        {
+            Ok(())
+        } else {
+            Err(Error::PathNotAllowed(path.to_path_buf()))
+        }
+    }
+
+    /// Checks a tunnel may connect to `host:port`, see `tunnel_targets`.
+    pub fn check_tunnel_target(&self, host: &str, port: u16) -> Result<(), Error> {
+        let unbracket = |host: &str| {
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string()
+        };
+        let host = unbracket(host);
+
+        let allowed = self.tunnel_targets.iter().any(|target| {
+            let Some((allowed_host, allowed_port)) = target.rsplit_once(':') else {
+                return false;
+            };
+            (allowed_host == "*" || unbracket(allowed_host).eq_ignore_ascii_case(&host))
+                && (allowed_port == "*" || allowed_port.parse() == Ok(port))
+        });
+        if allowed {
+            Ok(())
+        } else {
+            Err(Error::TunnelTargetNotAllowed(format!("{host}:{port}")))
+        }
+    }
+
+    /// Lists `limit` entries of directory `path` from `offset` on, sorted by name, along with
+    /// the number of entries in the whole directory.
+    pub async fn list_dir(
+        &self,
+        path: &Path,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<FileEntry>, usize), Error> {
+        self.check_path_allowed(path)?;
+
+        let mut names = vec![];
+        let mut dir = tokio::fs::read_dir(path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            names.push(entry.file_name());
+        }
+        names.sort();
+
+        let mut entries = vec![];
+        for name in names.iter().skip(offset).take(limit) {
+            match file_entry(&path.join(name)).await {
+                Ok(entry) => entries.push(entry),
+                // Removed since it was listed.
+                Err(Error::TokioIoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok((entries, names.len()))
+    }
+
+    pub async fn stat(&self, path: &Path) -> Result<FileEntry, Error> {
+        self.check_path_allowed(path)?;
+        file_entry(path).await
+    }
+
+    /// Reads up to `len` bytes of file `path` from `offset` on, capped at [`MAX_READ_LEN`],
+    /// along with the size of the whole file.
+    pub async fn read_file_range(
+        &self,
+        path: &Path,
+        offset: u64,
+        len: u64,
+    ) -> Result<(Vec<u8>, u64), Error> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        self.check_path_allowed(path)?;
+
+        let mut file = tokio::fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+
+        let mut data = vec![];
+        file.take(len.min(MAX_READ_LEN))
+            .read_to_end(&mut data)
+            .await?;
+
+        Ok((data, size))
+    }
+
+    /// Replaces the content of file `path` with `content` atomically, keeping the previous
+    /// content as a sibling backup file if `backup` is set.
+    ///
+    /// The file keeps its permissions and owner unless `mode` is given.
+    pub async fn write_file(
+        &self,
+        path: &Path,
+        content: &[u8],
+        mode: Option<u32>,
+        backup: bool,
+    ) -> Result<FileWrite, Error> {
+        self.check_path_allowed(path)?;
+        // Write through a symlink instead of replacing it, to where the allow-list check
+        // looked at.
+        let path = &resolve_path(path)?;
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(Error::NotAbsolutePath(path.to_path_buf()));
+        };
+        let name = name.to_string_lossy();
+
+        let previous = match tokio::fs::metadata(path).await {
+            Ok(metadata) => Some((metadata, crate::transfer::sha256_file(path).await?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        let backup_path = match &previous {
+            Some((metadata, _)) if backup => {
+                let backup_path = dir.join(format!("{name}.{millis}.bak"));
+                let mut backup = create_new(&backup_path).await?;
+                // As readable as the file itself, no more.
+                backup.set_permissions(metadata.permissions()).await?;
+                tokio::io::copy(&mut tokio::fs::File::open(path).await?, &mut backup).await?;
+                backup.sync_all().await?;
+                Some(backup_path)
+            }
+            _ => None,
+        };
+
+        // Next to the file, so the rename stays on the same file system.
+        let (tmp, mut file) = create_tmp(dir, &name).await?;
+        let res = async {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+                if let Some(mode) = mode {
+                    file.set_permissions(std::fs::Permissions::from_mode(mode))
+                        .await?;
+                } else if let Some((metadata, _)) = &previous {
+                    file.set_permissions(metadata.permissions()).await?;
+                }
+                if let Some((metadata, _)) = &previous {
+                    // Only possible with enough privileges, the agent's own user owns it otherwise.
+                    let (uid, gid) = (Some(metadata.uid()), Some(metadata.gid()));
+                    if let Err(e) = std::os::unix::fs::fchown(&file, uid, gid) {
+                        tracing::debug!("keep owner of {path:?} failed: {e:?}");
+                    }
+                }
+            }
+            #[cfg(not(unix))]
+            if mode.is_some() {
+                return Err(Error::UnsupportedRequest("write file with mode".into()));
+            }
+
+            tokio::io::AsyncWriteExt::write_all(&mut file, content).await?;
+            file.sync_all().await?;
+            drop(file);
+
+            tokio::fs::rename(&tmp, path).await?;
+
+            #[cfg(unix)]
+            tokio::fs::File::open(dir).await?.sync_all().await?;
+
+            Result::<(), Error>::Ok(())
+        };
+        if let Err(e) = res.await {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(e);
+        }
+
+        Ok(FileWrite {
+            size: content.len() as u64,
+            before_sha256: previous.map(|(_, sha256)| sha256),
+            after_sha256: format!("{:x}", Sha256::digest(content)),
+            backup_path,
+        })
+    }
+
+    /// Writes backup `backup_path` of file `path` back, the latest backup if unset.
+    ///
+    /// Returns the backup that was restored.
+    pub async fn restore_backup(
+        &self,
+        path: &Path,
+        backup_path: Option<PathBuf>,
+    ) -> Result<(PathBuf, FileWrite), Error> {
+        self.check_path_allowed(path)?;
+
+        let backup_path = match backup_path {
+            Some(backup_path) => {
+                self.check_path_allowed(&backup_path)?;
+                backup_path
+            }
+            // Backups are next to the file a symlink points to, see `write_file`.
+            None => latest_backup(&resolve_path(path)?)
+                .await?
+                .ok_or_else(|| Error::NotFoundBackup(path.to_path_buf()))?,
+        };
+
+        let content = tokio::fs::read(&backup_path).await?;
+
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(
+                tokio::fs::metadata(&backup_path)
+                    .await?
+                    .permissions()
+                    .mode(),
+            )
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+
+        let write = self.write_file(path, &content, mode, false).await?;
+
+        Ok((backup_path, write))
+    }
+}
+
+/// What [`Agent::write_file`] did.
+#[derive(Debug)]
+pub struct FileWrite {
+    pub size: u64,
+    /// None if the file didn't exist.
+    pub before_sha256: Option<String>,
+    pub after_sha256: String,
+    pub backup_path: Option<PathBuf>,
+}
+
+/// The most recent backup [`Agent::write_file`] made of `path`.
+async fn latest_backup(path: &Path) -> Result<Option<PathBuf>, Error> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Ok(None);
+    };
+    let prefix = format!("{}.", name.to_string_lossy());
+
+    let mut latest = None;
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        let Some(millis) = file_name
+            .to_str()
+            .and_then(|n| n.strip_prefix(&prefix))
+            .and_then(|n| n.strip_suffix(".bak"))
+            .and_then(|n| n.parse::<u128>().ok())
+        else {
+            continue;
+        };
+        // Others may have planted one, like a symlink to a file they can't read.
+        let metadata = tokio::fs::symlink_metadata(entry.path()).await?;
+        if check_own(&entry.path(), &metadata).is_err() {
+            tracing::debug!("skip backup {:?} not of the agent's own", entry.path());
+            continue;
+        }
+        match &latest {
+            Some((latest_millis, _)) if *latest_millis >= millis => {}
+            _ => latest = Some((millis, entry.path())),
+        }
+    }
+
+    Ok(latest.map(|(_, path)| path))
+}
+
+/// The most a single `ReadFileRange` request returns, to stay below the NATS payload limit.
+pub const MAX_READ_LEN: u64 = 512 * 1024;
+
+/// `path` with symlinks and `..` resolved, the part of it that doesn't exist yet is kept as is.
+fn resolve_path(path: &Path) -> Result<PathBuf, Error> {
+    let mut existing = path;
+    let mut rest = vec![];
+    loop {
+        match std::fs::canonicalize(existing) {
+            Ok(mut resolved) => {
+                resolved.extend(rest.iter().rev());
+                return Ok(resolved);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let (Some(parent), Some(name)) = (existing.parent(), existing.file_name()) else {
+                    return Err(e.into());
+                };
+                rest.push(name);
+                existing = parent;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+async fn file_entry(path: &Path) -> Result<FileEntry, Error> {
+    let metadata = tokio::fs::symlink_metadata(path).await?;
+
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_symlink() {
+        FileKind::Symlink
+    } else if file_type.is_dir() {
+        FileKind::Dir
+    } else if file_type.is_file() {
+        FileKind::File
+    } else {
+        FileKind::Other
+    };
+
+    let symlink_target = if file_type.is_symlink() {
+        tokio::fs::read_link(path)
+            .await
+            .ok()
+            .map(|target| target.to_string_lossy().to_string())
+    } else {
+        None
+    };
+
+    #[cfg(unix)]
+    let (mode, owner) = {
+        use std::os::unix::fs::MetadataExt;
+        (
+            Some(metadata.mode() & 0o7777),
+            crate::user::user_name(metadata.uid()),
+        )
+    };
+    #[cfg(not(unix))]
+    let (mode, owner) = (None, None);
+
+    Ok(FileEntry {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.to_string_lossy().to_string()),
+        kind,
+        size: metadata.len(),
+        mode,
+        owner,
+        modified: metadata.modified().ok(),
+        symlink_target,
+    })
+}
+
+/// Requests only handled on Windows, they fail or do nothing elsewhere.
+const WINDOWS_FUNCTIONS: &[&str] = &[
+    "patchmgmt",
+    "winservices",
+    "winsvcdetail",
+    "winsvcaction",
+    "editwinsvc",
+    "softwarelist",
+    "wmi",
+    "installchoco",
+    "installwithchoco",
+    "getwinupdates",
+    "installwinupdates",
+];
+
+/// Requests only handled on Unix.
+const UNIX_FUNCTIONS: &[&str] = &["openshell", "resizeshell", "closeshell"];
+
+/// The `func` of every request this build handles on this OS.
+pub fn supported_functions() -> Vec<String> {
+    IronhiveRequest::FUNCTIONS
+        .iter()
+        .filter(|func| cfg!(windows) || !WINDOWS_FUNCTIONS.contains(func))
+        .filter(|func| cfg!(unix) || !UNIX_FUNCTIONS.contains(func))
+        .map(|func| func.to_string())
+        .collect()
+}
+
+/// The names of the script modes that can run on this agent.
+pub fn supported_script_modes() -> Vec<String> {
+    [
+        ("PowerShell", ScriptMode::PowerShell),
+        (
+            "Binary",
+            ScriptMode::Binary {
+                path: PathBuf::new(),
+                ext: String::new(),
+            },
+        ),
+        ("Cmd", ScriptMode::Cmd),
+        ("Directly", ScriptMode::Directly),
+        ("Bash", ScriptMode::Bash),
+        ("Sh", ScriptMode::Sh),
+        ("Python", ScriptMode::Python),
+        ("Pwsh", ScriptMode::Pwsh),
+        ("Perl", ScriptMode::Perl),
+    ]
+    .into_iter()
+    .filter(|(_, mode)| crate::cmd::script_mode_supported(mode))
+    .map(|(name, _)| name.to_string())
+    .collect()
 }
 
 pub fn logged_on_user() -> String {
@@ -169,8 +626,14 @@ pub async fn reboot_now() -> Result<(), Error> {
         CmdExe {
             exe: "shutdown.exe",
             args: vec!["/r", "/t", "5", "/f"],
-            detached: false,
+            detached: None,
             timeout: Duration::from_secs(15),
+            output: None,
+            job: None,
+            run_as_user: None,
+            stdin: None,
+            cwd: None,
+            max_output_bytes: None,
         }
         .run()
         .await?;
@@ -180,8 +643,14 @@ pub async fn reboot_now() -> Result<(), Error> {
         CmdExe::<_, &str> {
             exe: "reboot",
             args: vec![],
-            detached: false,
+            detached: None,
             timeout: Duration::from_secs(15),
+            output: None,
+            job: None,
+            run_as_user: None,
+            stdin: None,
+            cwd: None,
+            max_output_bytes: None,
         }
         .run()
         .await?;
@@ -223,8 +692,14 @@ pub async fn system_reboot_required() -> bool {
             let res = CmdExe {
                 exe: bin,
                 args: vec!["-r"],
-                detached: false,
+                detached: None,
                 timeout: Duration::from_secs(15),
+                output: None,
+                job: None,
+                run_as_user: None,
+                stdin: None,
+                cwd: None,
+                max_output_bytes: None,
             }
             .run()
             .await;
@@ -261,6 +736,19 @@ pub fn patch_mgmnt(enable: bool) -> Result<(), Error> {
     Ok(())
 }
 
+/// Creates a file with a random name next to `name` in `dir` to write it anew.
+async fn create_tmp(dir: &Path, name: &str) -> Result<(PathBuf, tokio::fs::File), Error> {
+    let mut n_try = 0u32;
+    loop {
+        let tmp = dir.join(format!(".{name}.{:016x}.tmp", rand::random::<u64>()));
+        match create_new(&tmp).await {
+            Ok(file) => return Ok((tmp, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && n_try < 1024 => n_try += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -281,4 +769,209 @@ mod tests {
         println!("{}", system_reboot_required().await);
         println!("{:?}", agent.get_procs_rpc());
     }
+
+    #[test]
+    fn test_capabilities() {
+        for func in IronhiveRequest::FUNCTIONS {
+            // Requests with fields fail on those, but never on the tag.
+            let res =
+                serde_json::from_value::<IronhiveRequest>(serde_json::json!({ "func": func }));
+            match res {
+                Ok(req) => assert_eq!(req.func(), *func),
+                Err(e) => assert!(e.to_string().contains("missing field"), "{func}: {e}"),
+            }
+        }
+        for func in WINDOWS_FUNCTIONS.iter().chain(UNIX_FUNCTIONS) {
+            assert!(IronhiveRequest::FUNCTIONS.contains(func), "{func}");
+        }
+
+        let functions = supported_functions();
+        assert!(functions.contains(&"ping".to_string()));
+        assert!(functions.contains(&"capabilities".to_string()));
+        assert_eq!(
+            functions.contains(&"winservices".to_string()),
+            cfg!(windows)
+        );
+
+        let script_modes = supported_script_modes();
+        assert!(script_modes.contains(&"Directly".to_string()));
+        assert_eq!(script_modes.contains(&"Cmd".to_string()), cfg!(windows));
+    }
+
+    #[tokio::test]
+    async fn test_file_requests() {
+        let root = std::env::temp_dir().join("ironhive-test-fs");
+        let _ = tokio::fs::remove_dir_all(&root).await;
+        let allowed = root.join("allowed");
+        tokio::fs::create_dir_all(allowed.join("sub"))
+            .await
+            .unwrap();
+        for name in ["b.txt", "a.txt", "c.txt"] {
+            tokio::fs::write(allowed.join(name), name).await.unwrap();
+        }
+        tokio::fs::write(root.join("secret.txt"), "secret")
+            .await
+            .unwrap();
+
+        let agent = Agent {
+            allowed_paths: vec![allowed.clone()],
+            ..Default::default()
+        };
+
+        let (entries, total) = agent.list_dir(&allowed, 1, 2).await.unwrap();
+        assert_eq!(total, 4);
+        assert_eq!(
+            entries.iter().map(|e| e.name.as_str()).collect::<Vec<_>>(),
+            ["b.txt", "c.txt"]
+        );
+        assert_eq!(entries[0].kind, FileKind::File);
+        assert_eq!(entries[0].size, 5);
+
+        let entry = agent.stat(&allowed.join("sub")).await.unwrap();
+        assert_eq!(entry.kind, FileKind::Dir);
+
+        let (data, size) = agent
+            .read_file_range(&allowed.join("a.txt"), 2, 100)
+            .await
+            .unwrap();
+        assert_eq!(data, b"txt");
+        assert_eq!(size, 5);
+
+        for path in [
+            root.join("secret.txt"),
+            allowed.join("..").join("secret.txt"),
+            root.clone(),
+        ] {
+            assert!(matches!(
+                agent.stat(&path).await,
+                Err(Error::PathNotAllowed(_))
+            ));
+        }
+        assert!(agent.check_path_allowed(&allowed.join("new.txt")).is_ok());
+
+        #[cfg(unix)]
+        {
+            let link = allowed.join("link");
+            std::os::unix::fs::symlink(root.join("secret.txt"), &link).unwrap();
+            assert!(matches!(
+                agent.read_file_range(&link, 0, 100).await,
+                Err(Error::PathNotAllowed(_))
+            ));
+            let (entries, _) = agent.list_dir(&allowed, 0, 10).await.unwrap();
+            let entry = entries.iter().find(|e| e.name == "link").unwrap();
+            assert_eq!(entry.kind, FileKind::Symlink);
+            assert_eq!(
+                entry.symlink_target.as_deref(),
+                Some(root.join("secret.txt").to_string_lossy().as_ref())
+            );
+
+            // A target that isn't UTF-8 still makes a reply.
+            use std::os::unix::ffi::OsStrExt;
+            let target = std::ffi::OsStr::from_bytes(b"not-\xffutf8");
+            std::os::unix::fs::symlink(target, allowed.join("odd")).unwrap();
+            let (entries, _) = agent.list_dir(&allowed, 0, 10).await.unwrap();
+            let entry = entries.iter().find(|e| e.name == "odd").unwrap();
+            assert_eq!(entry.symlink_target.as_deref(), Some("not-\u{fffd}utf8"));
+            assert!(serde_json::to_vec(&entries).is_ok());
+        }
+
+        let _ = tokio::fs::remove_dir_all(&root).await;
+    }
+
+    #[tokio::test]
+    async fn test_write_file() {
+        let dir = std::env::temp_dir().join("ironhive-test-write");
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("app.conf");
+
+        let agent = Agent::default();
+        let sha256 = |data: &[u8]| format!("{:x}", Sha256::digest(data));
+
+        let write = agent.write_file(&path, b"v1", None, true).await.unwrap();
+        assert_eq!(write.before_sha256, None);
+        assert_eq!(write.after_sha256, sha256(b"v1"));
+        assert_eq!(write.backup_path, None);
+
+        let write = agent.write_file(&path, b"v2", None, true).await.unwrap();
+        assert_eq!(write.before_sha256, Some(sha256(b"v1")));
+        let backup_path = write.backup_path.unwrap();
+        assert_eq!(tokio::fs::read(&backup_path).await.unwrap(), b"v1");
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"v2");
+
+        let (restored, write) = agent.restore_backup(&path, None).await.unwrap();
+        assert_eq!(restored, backup_path);
+        assert_eq!(write.before_sha256, Some(sha256(b"v2")));
+        assert_eq!(write.after_sha256, sha256(b"v1"));
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"v1");
+
+        // Only the file and its backup, no temporary file left over.
+        let mut entries = tokio::fs::read_dir(&dir).await.unwrap();
+        let mut count = 0;
+        while entries.next_entry().await.unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 2);
+
+        // A newer backup planted as a symlink is not restored from.
+        #[cfg(unix)]
+        {
+            let secret = dir.join("secret");
+            tokio::fs::write(&secret, b"secret").await.unwrap();
+            std::os::unix::fs::symlink(&secret, dir.join(format!("app.conf.{}.bak", u64::MAX)))
+                .unwrap();
+            let (restored, _) = agent.restore_backup(&path, None).await.unwrap();
+            assert_eq!(restored, backup_path);
+            assert_eq!(tokio::fs::read(&path).await.unwrap(), b"v1");
+        }
+
+        assert!(matches!(
+            agent.restore_backup(&dir.join("other.conf"), None).await,
+            Err(Error::NotFoundBackup(_))
+        ));
+
+        // Writing through a symlink keeps the link and changes the file it points to.
+        #[cfg(unix)]
+        {
+            let link = dir.join("link.conf");
+            std::os::unix::fs::symlink(&path, &link).unwrap();
+            let write = agent.write_file(&link, b"v3", None, true).await.unwrap();
+            assert_eq!(write.before_sha256, Some(sha256(b"v1")));
+            let meta = tokio::fs::symlink_metadata(&link).await.unwrap();
+            assert!(meta.file_type().is_symlink());
+            assert_eq!(tokio::fs::read(&path).await.unwrap(), b"v3");
+            let backup_path = write.backup_path.unwrap();
+            assert!(backup_path.starts_with(dir.canonicalize().unwrap()));
+
+            let (restored, _) = agent.restore_backup(&link, None).await.unwrap();
+            assert_eq!(restored, backup_path);
+            assert!(tokio::fs::symlink_metadata(&link)
+                .await
+                .unwrap()
+                .file_type()
+                .is_symlink());
+            assert_eq!(tokio::fs::read(&path).await.unwrap(), b"v1");
+        }
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[test]
+    fn test_tunnel_targets() {
+        let mut agent = Agent::default();
+        assert!(agent.check_tunnel_target("localhost", 5432).is_ok());
+        assert!(agent.check_tunnel_target("LOCALHOST", 22).is_ok());
+        assert!(agent.check_tunnel_target("::1", 22).is_ok());
+        assert!(agent.check_tunnel_target("[::1]", 22).is_ok());
+        assert!(matches!(
+            agent.check_tunnel_target("10.0.0.1", 22),
+            Err(Error::TunnelTargetNotAllowed(target)) if target == "10.0.0.1:22"
+        ));
+
+        agent.tunnel_targets = vec!["db.internal:5432".into(), "*:443".into()];
+        assert!(agent.check_tunnel_target("db.internal", 5432).is_ok());
+        assert!(agent.check_tunnel_target("db.internal", 22).is_err());
+        assert!(agent.check_tunnel_target("example.com", 443).is_ok());
+        assert!(agent.check_tunnel_target("localhost", 5432).is_err());
+    }
 }
@@ -3,7 +3,8 @@ use crate::{
     error::Error,
 };
 use shared::{
-    AgentInfoNats, AgentMode, CheckInNats, PublicIPNats, WinDisksNats, WinSvcNats, WinWMINats,
+    AgentInfoNats, AgentMode, AgentOfflineNats, CheckInNats, PublicIPNats, WinDisksNats,
+    WinSvcNats, WinWMINats,
 };
 use sysinfo::SystemExt;
 
@@ -101,4 +102,19 @@ impl crate::agent::Agent {
             .await?;
         Ok(())
     }
+    /// Tells the server the agent is going offline.
+    pub async fn offline_message(&self, client: &async_nats::Client) -> Result<(), Error> {
+        let offline = AgentOfflineNats {
+            agent_id: self.agent_id.clone(),
+            version: self.version().into(),
+        };
+        client
+            .publish_with_reply(
+                self.agent_id.clone(),
+                "agent-offline".to_string(),
+                serde_json::to_vec(&offline)?.into(),
+            )
+            .await?;
+        Ok(())
+    }
 }
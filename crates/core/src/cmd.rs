@@ -1,19 +1,181 @@
-use std::{collections::HashMap, ffi::OsStr, process::Output, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Output, Stdio},
+    time::{Duration, SystemTime},
+};
 
-use shared::ScriptMode;
-use tokio::process::Command;
+use bytes::Bytes;
+use shared::{OutputStream, ScriptMode, Termination};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    process::{Child, Command},
+    sync::{mpsc, oneshot},
+};
+use tracing::{debug, warn};
 
-use crate::error::Error;
+use crate::{error::Error, jobs::JobHandle};
+
+/// A piece of output produced by a running child process.
+#[derive(Debug)]
+pub struct OutputChunk {
+    pub stream: OutputStream,
+    pub data: Bytes,
+}
+
+/// Receives the output of a child process while it is running.
+///
+/// Bounded, a receiver that can't keep up slows down reading the output instead of the
+/// agent buffering it all.
+pub type OutputSender = mpsc::Sender<OutputChunk>;
+
+/// How many chunks of at most 8 KiB an [`OutputSender`] holds.
+pub const OUTPUT_CHANNEL_CAPACITY: usize = 64;
+
+/// What a finished command left behind.
+#[derive(Debug, Clone)]
+pub struct CmdOutput {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// The command ran past its timeout, so its process tree was killed and
+    /// the output is what was collected until then.
+    pub timed_out: bool,
+    /// The middle of stdout or stderr was dropped to stay within `max_output_bytes`.
+    pub truncated: bool,
+    /// The job of the command was cancelled, so its process tree was killed.
+    pub cancelled: bool,
+    /// Set when the command was started detached, it is still running and
+    /// `status`, `stdout` and `stderr` are empty.
+    pub detached: Option<Detached>,
+}
+
+impl CmdOutput {
+    /// The exit code, or 128 plus the signal number like shells report it.
+    pub fn retcode(&self) -> i32 {
+        match (self.status.code(), self.signal()) {
+            (Some(code), _) => code,
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => -1,
+        }
+    }
+
+    /// The signal that terminated the command, always `None` on Windows.
+    pub fn signal(&self) -> Option<i32> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            self.status.signal()
+        }
+        #[cfg(not(unix))]
+        {
+            None
+        }
+    }
+
+    /// The name of [`CmdOutput::signal`], like `SIGSEGV`.
+    pub fn signal_name(&self) -> Option<String> {
+        self.signal().map(signal_name)
+    }
+
+    pub fn core_dumped(&self) -> bool {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            self.status.core_dumped()
+        }
+        #[cfg(not(unix))]
+        {
+            false
+        }
+    }
+
+    pub fn termination(&self) -> Termination {
+        if self.timed_out {
+            Termination::TimedOut
+        } else if self.cancelled {
+            Termination::Cancelled
+        } else if self.signal().is_some() {
+            Termination::Signaled
+        } else {
+            Termination::Exited
+        }
+    }
+}
+
+#[cfg(unix)]
+fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGCHLD => "SIGCHLD",
+        libc::SIGCONT => "SIGCONT",
+        libc::SIGSTOP => "SIGSTOP",
+        libc::SIGTSTP => "SIGTSTP",
+        libc::SIGTTIN => "SIGTTIN",
+        libc::SIGTTOU => "SIGTTOU",
+        libc::SIGURG => "SIGURG",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGVTALRM => "SIGVTALRM",
+        libc::SIGPROF => "SIGPROF",
+        libc::SIGWINCH => "SIGWINCH",
+        libc::SIGIO => "SIGIO",
+        libc::SIGSYS => "SIGSYS",
+        _ => return format!("SIG{signal}"),
+    };
+    name.to_string()
+}
+
+#[cfg(not(unix))]
+fn signal_name(signal: i32) -> String {
+    format!("SIG{signal}")
+}
+
+/// A child started in detached mode.
+#[derive(Debug, Clone)]
+pub struct Detached {
+    pub pid: u32,
+    pub stdout_path: PathBuf,
+    pub stderr_path: PathBuf,
+}
 
 #[derive(Debug)]
 pub struct CmdOptions<P = String, A = String, K = String, V = String> {
-    /// have a child process that is in a different process group so that
-    /// parent terminating doesn't kill child
-    pub detached: bool,
+    /// have a child process that is in a different session (process group on Windows)
+    /// so that parent terminating doesn't kill child, [`CmdOptions::run`] does
+    /// not wait for it and its stdout and stderr go to log files in this directory
+    pub detached: Option<PathBuf>,
     pub program: P,
     pub args: Vec<A>,
     pub env_vars: Vec<(K, V)>,
     pub timeout: Duration,
+    /// Forward stdout and stderr chunks here as soon as they are read.
+    pub output: Option<OutputSender>,
+    /// The job this execution is registered as, it can be cancelled through it.
+    pub job: Option<JobHandle>,
+    /// Run as this user instead of the agent's own, Unix only.
+    pub run_as_user: Option<String>,
+    /// Written to stdin, which is closed afterwards. Without it stdin is null.
+    pub stdin: Option<Vec<u8>>,
+    pub cwd: Option<PathBuf>,
+    /// Keep at most this many bytes of each of stdout and stderr,
+    /// [`DEFAULT_MAX_OUTPUT_BYTES`] if unset.
+    pub max_output_bytes: Option<usize>,
 }
 
 impl<P, A, K, V> CmdOptions<P, A, K, V>
@@ -23,59 +185,510 @@ where
     K: AsRef<OsStr>,
     V: AsRef<OsStr>,
 {
-    fn command(self) -> (Command, Duration) {
+    fn command(self) -> Result<(Command, Supervisor), Error> {
         let Self {
             detached,
             program,
             args,
             env_vars,
             timeout,
+            output,
+            job,
+            run_as_user,
+            stdin,
+            cwd,
+            max_output_bytes,
         } = self;
+        let detached = detached.is_some();
+
+        let mut cmd = std::process::Command::new(program);
+        cmd.args(args)
+            .envs(env_vars)
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
 
-        let mut cmd = Command::new(program);
-        cmd.args(args).envs(env_vars);
+        if let Some(cwd) = cwd {
+            cmd.current_dir(cwd);
+        }
 
+        #[cfg(unix)]
+        if !detached {
+            use std::os::unix::process::CommandExt;
+            // Lead a new process group, so the whole process tree can be killed at once.
+            cmd.process_group(0);
+        }
+
+        #[cfg(unix)]
         if detached {
-            #[cfg(windows)]
-            {
-                use ::windows::Win32::System::Threading::{
-                    CREATE_NEW_PROCESS_GROUP, DETACHED_PROCESS,
-                };
-                cmd.creation_flags((DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP).0);
+            use std::os::unix::process::CommandExt;
+            // Safety: `setsid` is async-signal-safe.
+            unsafe {
+                cmd.pre_exec(|| {
+                    // Lead a new session, so the child survives the agent and its terminal.
+                    if libc::setsid() == -1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
             }
+        }
+
+        #[cfg(windows)]
+        if detached {
+            use ::windows::Win32::System::Threading::{CREATE_NEW_PROCESS_GROUP, DETACHED_PROCESS};
+            use std::os::windows::process::CommandExt;
+            cmd.creation_flags((DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP).0);
+        }
+
+        if let Some(name) = run_as_user {
             #[cfg(unix)]
             {
-                // TODO:
-                cmd.gid(0);
+                use std::os::unix::process::CommandExt;
+
+                let user = crate::user::User::lookup(&name)?;
+                debug!("run as {user:?}");
+
+                // The user's environment, explicit `env_vars` still win.
+                let mut user_env = vec![
+                    ("HOME", user.home.as_os_str()),
+                    ("USER", OsStr::new(&user.name)),
+                    ("LOGNAME", OsStr::new(&user.name)),
+                    ("SHELL", user.shell.as_os_str()),
+                ];
+                let explicit = cmd
+                    .get_envs()
+                    .map(|(k, _)| k.to_owned())
+                    .collect::<Vec<_>>();
+                user_env.retain(|(k, _)| !explicit.iter().any(|e| e == k));
+                cmd.envs(user_env);
+
+                let crate::user::User {
+                    uid, gid, groups, ..
+                } = user;
+                // std sets the uid before running `pre_exec`, which would leave no
+                // privilege to set the groups, so switch all of them here.
+                // Safety: the closure only makes async-signal-safe calls.
+                unsafe {
+                    cmd.pre_exec(move || crate::user::switch_to(uid, gid, &groups));
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                return Err(Error::UnsupportedRequest(format!("run as user {name}")));
             }
         }
-        (cmd, timeout)
+
+        Ok((
+            Command::from(cmd),
+            Supervisor {
+                timeout,
+                output,
+                job,
+                stdin,
+                max_output_bytes: max_output_bytes.unwrap_or(DEFAULT_MAX_OUTPUT_BYTES),
+            },
+        ))
     }
-    async fn run(self) -> Result<Output, Error> {
-        let (mut cmd, timeout) = self.command();
 
-        let output = tokio::time::timeout(timeout, cmd.output()).await??;
+    async fn run(self) -> Result<CmdOutput, Error> {
+        let log_dir = self.detached.clone();
+        let (mut cmd, supervisor) = self.command()?;
 
-        Ok(output)
+        if let Some(log_dir) = log_dir {
+            return spawn_detached(cmd, &log_dir, supervisor.stdin).await;
+        }
+        supervisor.wait(cmd.spawn()?).await
     }
 
     #[cfg(windows)]
-    async fn run_with_raw(self, raw: impl AsRef<std::ffi::OsStr>) -> Result<Output, Error> {
-        let (mut cmd, timeout) = self.command();
+    async fn run_with_raw(self, raw: impl AsRef<std::ffi::OsStr>) -> Result<CmdOutput, Error> {
+        let log_dir = self.detached.clone();
+        let (mut cmd, supervisor) = self.command()?;
 
         cmd.raw_arg(raw);
 
-        let output = tokio::time::timeout(timeout, cmd.output()).await??;
+        if let Some(log_dir) = log_dir {
+            return spawn_detached(cmd, &log_dir, supervisor.stdin).await;
+        }
+        supervisor.wait(cmd.spawn()?).await
+    }
+}
+
+/// Starts `cmd` without waiting for it, its stdout and stderr go to log files in `dir`.
+async fn spawn_detached(
+    mut cmd: Command,
+    dir: &Path,
+    stdin: Option<Vec<u8>>,
+) -> Result<CmdOutput, Error> {
+    crate::temp_file::create_private_dir(dir).await?;
+    remove_old_logs(dir).await;
+
+    let name = format!(
+        "{}-{}",
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis(),
+        crate::utils::random_id()
+    );
+    let stdout_path = dir.join(format!("{name}.stdout.log"));
+    let stderr_path = dir.join(format!("{name}.stderr.log"));
+
+    let stdout = crate::temp_file::create_new(&stdout_path).await?;
+    let stderr = crate::temp_file::create_new(&stderr_path).await?;
+    cmd.stdout(stdout.into_std().await)
+        .stderr(stderr.into_std().await);
+
+    // The child is not killed on drop, tokio reaps it in the background once it exits.
+    let mut child = cmd.spawn()?;
+    let pid = child.id().unwrap_or_default();
+    feed_stdin(&mut child, stdin);
+    debug!("detached child {pid}, logs in {stdout_path:?} and {stderr_path:?}");
+
+    Ok(CmdOutput {
+        status: ExitStatus::default(),
+        stdout: vec![],
+        stderr: vec![],
+        timed_out: false,
+        truncated: false,
+        cancelled: false,
+        detached: Some(Detached {
+            pid,
+            stdout_path,
+            stderr_path,
+        }),
+    })
+}
+
+/// Detached logs untouched for longer than this are removed.
+const DETACHED_LOG_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Removes the logs of detached children that haven't been written to for
+/// [`DETACHED_LOG_RETENTION`], children still running keep theirs.
+async fn remove_old_logs(dir: &Path) {
+    let res = async {
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let meta = entry.metadata().await?;
+            let expired = meta
+                .modified()
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > DETACHED_LOG_RETENTION);
+            if meta.is_file() && expired {
+                debug!("remove old detached log {:?}", entry.path());
+                tokio::fs::remove_file(entry.path()).await?;
+            }
+        }
+        Result::<(), std::io::Error>::Ok(())
+    };
+    if let Err(e) = res.await {
+        warn!("clean up detached logs in {dir:?} failed: {e:?}");
+    }
+}
+
+/// Writes `data` to the stdin of `child` in the background, then closes it.
+fn feed_stdin(child: &mut Child, data: Option<Vec<u8>>) {
+    if let (Some(mut stdin), Some(data)) = (child.stdin.take(), data) {
+        tokio::spawn(async move {
+            // The child may exit or close stdin without reading all of it.
+            if let Err(e) = stdin.write_all(&data).await {
+                debug!("write stdin failed: {e:?}");
+            }
+        });
+    }
+}
+
+/// Watches over a running child process.
+struct Supervisor {
+    timeout: Duration,
+    output: Option<OutputSender>,
+    job: Option<JobHandle>,
+    stdin: Option<Vec<u8>>,
+    max_output_bytes: usize,
+}
+
+/// Used when a command does not set `max_output_bytes`.
+pub const DEFAULT_MAX_OUTPUT_BYTES: usize = 256 * 1024;
+
+/// Collects the output of a stream up to a size limit.
+///
+/// Once the limit is reached, the first half and the last half of the limit are kept,
+/// as the start and the end of an output are usually the interesting parts.
+struct CappedBuffer {
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    head_cap: usize,
+    tail_cap: usize,
+    total: usize,
+}
+
+impl CappedBuffer {
+    fn new(cap: usize) -> Self {
+        Self {
+            head: vec![],
+            tail: VecDeque::new(),
+            head_cap: cap - cap / 2,
+            tail_cap: cap / 2,
+            total: 0,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.total += data.len();
+
+        let n = data.len().min(self.head_cap - self.head.len());
+        self.head.extend_from_slice(&data[..n]);
+
+        let data = &data[n..];
+        // Only the last `tail_cap` bytes can survive.
+        let data = &data[data.len().saturating_sub(self.tail_cap)..];
+        let excess = (self.tail.len() + data.len()).saturating_sub(self.tail_cap);
+        self.tail.drain(..excess);
+        self.tail.extend(data);
+    }
+
+    fn truncated(&self) -> bool {
+        self.total > self.head.len() + self.tail.len()
+    }
+
+    fn into_vec(self) -> Vec<u8> {
+        let mut vec = self.head;
+        vec.extend(self.tail);
+        vec
+    }
+}
+
+/// How long a timed out process tree gets to exit after SIGTERM before it is killed.
+const TERMINATE_GRACE: Duration = Duration::from_secs(5);
+
+/// How long to keep reading output left in the pipes once the process tree was killed.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+enum Interrupt {
+    TimedOut,
+    Cancelled(oneshot::Sender<Output>),
+}
+
+impl Supervisor {
+    /// Waits for `child` to exit while collecting its output.
+    ///
+    /// When the timeout fires or the job is cancelled, the process tree is killed
+    /// and the output collected so far is returned.
+    async fn wait(self, mut child: Child) -> Result<CmdOutput, Error> {
+        async fn read_to_end<R: AsyncRead + Unpin>(
+            io: Option<R>,
+            stream: OutputStream,
+            sender: Option<&OutputSender>,
+            vec: &mut CappedBuffer,
+        ) -> Result<(), Error> {
+            let Some(mut io) = io else {
+                return Ok(());
+            };
+            let mut buf = vec![0; 8 * 1024];
+            loop {
+                let n = io.read(&mut buf).await?;
+                if n == 0 {
+                    return Ok(());
+                }
+                vec.push(&buf[..n]);
+                if let Some(sender) = sender {
+                    // The receiver going away must not break the command.
+                    let _ = sender
+                        .send(OutputChunk {
+                            stream,
+                            data: Bytes::copy_from_slice(&buf[..n]),
+                        })
+                        .await;
+                }
+            }
+        }
+
+        let Self {
+            timeout,
+            output,
+            mut job,
+            stdin,
+            max_output_bytes,
+        } = self;
+
+        feed_stdin(&mut child, stdin);
+
+        let pid = child.id();
+        if let Some(job) = &job {
+            debug!("job {} runs as pid {pid:?}", job.id());
+            job.set_pid(pid);
+        }
+
+        let mut stdout = CappedBuffer::new(max_output_bytes);
+        let mut stderr = CappedBuffer::new(max_output_bytes);
+        let (stdout_pipe, stderr_pipe) = (child.stdout.take(), child.stderr.take());
+        let mut reads = Box::pin(async {
+            tokio::try_join!(
+                read_to_end(
+                    stdout_pipe,
+                    OutputStream::Stdout,
+                    output.as_ref(),
+                    &mut stdout
+                ),
+                read_to_end(
+                    stderr_pipe,
+                    OutputStream::Stderr,
+                    output.as_ref(),
+                    &mut stderr
+                ),
+            )
+        });
+
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+        // The output is complete once the pipes are closed, which may be later than the child exiting.
+        let mut reads_done = false;
+        let mut status = None;
+        let interrupt = loop {
+            tokio::select! {
+                res = &mut reads, if !reads_done => {
+                    res?;
+                    reads_done = true;
+                }
+                res = child.wait(), if status.is_none() => status = Some(res?),
+                _ = &mut deadline => break Some(Interrupt::TimedOut),
+                reply = cancelled(job.as_mut()) => break Some(Interrupt::Cancelled(reply)),
+            }
+            if reads_done && status.is_some() {
+                break None;
+            }
+        };
+
+        let timed_out = matches!(interrupt, Some(Interrupt::TimedOut));
+        let mut cancelled = None;
+        match interrupt {
+            None => {}
+            Some(Interrupt::TimedOut) => {
+                debug!("timed out after {timeout:?}, terminate process tree of {pid:?}");
+                terminate_tree(&mut child, pid).await?;
+            }
+            Some(Interrupt::Cancelled(reply)) => {
+                debug!("cancel job, kill process tree of {pid:?}");
+                kill_tree(&mut child, pid).await?;
+                cancelled = Some(reply);
+            }
+        }
+
+        let status = match status {
+            Some(status) => status,
+            None => child.wait().await?,
+        };
+
+        if !reads_done {
+            // Processes that left the process group may still hold the pipes open.
+            if let Ok(res) = tokio::time::timeout(DRAIN_TIMEOUT, &mut reads).await {
+                res?;
+            }
+        }
+        drop(reads);
 
-        Ok(output)
+        let truncated = stdout.truncated() || stderr.truncated();
+        let (stdout, stderr) = (stdout.into_vec(), stderr.into_vec());
+
+        let was_cancelled = cancelled.is_some();
+        if let Some(reply) = cancelled {
+            let _ = reply.send(Output {
+                status,
+                stdout: stdout.clone(),
+                stderr: stderr.clone(),
+            });
+        }
+
+        Ok(CmdOutput {
+            status,
+            stdout,
+            stderr,
+            timed_out,
+            truncated,
+            cancelled: was_cancelled,
+            detached: None,
+        })
+    }
+}
+
+/// Resolves once `job` is cancelled, never if there is no job.
+async fn cancelled(job: Option<&mut JobHandle>) -> oneshot::Sender<Output> {
+    match job {
+        Some(job) => job.cancelled().await,
+        None => std::future::pending().await,
     }
 }
 
+/// Asks `child` and the processes it started to exit, and kills them if they
+/// are still there after [`TERMINATE_GRACE`].
+async fn terminate_tree(child: &mut Child, pid: Option<u32>) -> Result<(), Error> {
+    #[cfg(unix)]
+    if let Some(pid) = pid {
+        // The child leads its own process group, see `CmdOptions::command`.
+        if unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGTERM) } == 0 {
+            if tokio::time::timeout(TERMINATE_GRACE, child.wait())
+                .await
+                .is_err()
+            {
+                debug!("process tree of {pid} is still alive, kill it");
+            }
+            // Kill whatever is left in the group, even if the child itself exited.
+            unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+            return Ok(());
+        }
+    }
+
+    // There is no graceful way to ask a Windows process to exit.
+    kill_tree(child, pid).await
+}
+
+/// Kills `child` together with the processes it started.
+async fn kill_tree(child: &mut Child, pid: Option<u32>) -> Result<(), Error> {
+    #[cfg(unix)]
+    if let Some(pid) = pid {
+        // The child leads its own process group, see `CmdOptions::command`.
+        if unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) } == 0 {
+            return Ok(());
+        }
+    }
+    #[cfg(windows)]
+    if let Some(pid) = pid {
+        let status = Command::new("taskkill")
+            .args(["/F", "/T", "/PID", &pid.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+        if matches!(status, Ok(status) if status.success()) {
+            return Ok(());
+        }
+    }
+    // The child may have exited already while its pipes are still held open
+    // by a process that left its group, there is nothing left to kill then.
+    if child.try_wait()?.is_none() {
+        child.start_kill()?;
+    }
+
+    Ok(())
+}
+
 pub struct CmdShell<S = String, C = String> {
     pub shell: S,
     pub command: C,
-    pub detached: bool,
+    pub detached: Option<PathBuf>,
     pub timeout: Duration,
+    pub output: Option<OutputSender>,
+    pub job: Option<JobHandle>,
+    pub run_as_user: Option<String>,
+    pub stdin: Option<Vec<u8>>,
+    pub cwd: Option<PathBuf>,
+    pub max_output_bytes: Option<usize>,
 }
 
 impl<S, C> CmdShell<S, C>
@@ -83,12 +696,18 @@ where
     S: AsRef<OsStr>,
     C: AsRef<OsStr>,
 {
-    pub async fn run(self) -> Result<Output, Error> {
+    pub async fn run(self) -> Result<CmdOutput, Error> {
         let Self {
             shell,
             command,
             detached,
             timeout,
+            output,
+            job,
+            run_as_user,
+            stdin,
+            cwd,
+            max_output_bytes,
         } = self;
         #[cfg(windows)]
         {
@@ -100,6 +719,12 @@ where
                     args: vec!["/C"],
                     env_vars: empty_vec(),
                     timeout,
+                    output,
+                    job,
+                    run_as_user,
+                    stdin,
+                    cwd,
+                    max_output_bytes,
                 }
                 .run_with_raw(command)
                 .await
@@ -114,6 +739,12 @@ where
                     ],
                     env_vars: empty_vec(),
                     timeout,
+                    output,
+                    job,
+                    run_as_user,
+                    stdin,
+                    cwd,
+                    max_output_bytes,
                 }
                 .run()
                 .await
@@ -129,6 +760,12 @@ where
                 args: vec!["-c".as_ref(), command.as_ref()],
                 env_vars: empty_vec(),
                 timeout,
+                output,
+                job,
+                run_as_user,
+                stdin,
+                cwd,
+                max_output_bytes,
             }
             .run()
             .await
@@ -140,8 +777,14 @@ where
 pub struct CmdExe<E = String, A = String> {
     pub exe: E,
     pub args: Vec<A>,
-    pub detached: bool,
+    pub detached: Option<PathBuf>,
     pub timeout: Duration,
+    pub output: Option<OutputSender>,
+    pub job: Option<JobHandle>,
+    pub run_as_user: Option<String>,
+    pub stdin: Option<Vec<u8>>,
+    pub cwd: Option<PathBuf>,
+    pub max_output_bytes: Option<usize>,
 }
 
 impl<E, A> CmdExe<E, A>
@@ -149,12 +792,18 @@ where
     E: AsRef<OsStr>,
     A: AsRef<OsStr>,
 {
-    pub async fn run(self) -> Result<Output, Error> {
+    pub async fn run(self) -> Result<CmdOutput, Error> {
         let Self {
             exe,
             args,
             detached,
             timeout,
+            output,
+            job,
+            run_as_user,
+            stdin,
+            cwd,
+            max_output_bytes,
         } = self;
 
         CmdOptions {
@@ -163,6 +812,12 @@ where
             args,
             env_vars: empty_vec(),
             timeout,
+            output,
+            job,
+            run_as_user,
+            stdin,
+            cwd,
+            max_output_bytes,
         }
         .run()
         .await
@@ -177,8 +832,14 @@ where
     pub mode: ScriptMode,
     pub args: Vec<A>,
     pub env_vars: HashMap<K, V>,
-    pub detached: bool,
+    pub detached: Option<PathBuf>,
     pub timeout: Duration,
+    pub output: Option<OutputSender>,
+    pub job: Option<JobHandle>,
+    pub run_as_user: Option<String>,
+    pub stdin: Option<Vec<u8>>,
+    pub cwd: Option<PathBuf>,
+    pub max_output_bytes: Option<usize>,
 }
 
 impl<C, A, K, V> CmdScript<C, A, K, V>
@@ -188,7 +849,7 @@ where
     K: AsRef<OsStr> + std::cmp::Eq + std::hash::Hash,
     V: AsRef<OsStr>,
 {
-    pub async fn run(self) -> Result<Output, Error> {
+    pub async fn run(self) -> Result<CmdOutput, Error> {
         let Self {
             code,
             mode,
@@ -196,86 +857,175 @@ where
             env_vars,
             detached,
             timeout,
+            output,
+            job,
+            run_as_user,
+            stdin,
+            cwd,
+            max_output_bytes,
         } = self;
 
-        let mut args = args.iter().map(|a| a.as_ref()).collect::<Vec<_>>();
-
         let ext = mode.ext();
+        let keep_script = detached.is_some();
 
-        let temp_file = crate::temp_file::TempFile::new(ext, code.as_ref().trim()).await?;
-        let temp_file_path = temp_file.path();
-        let res = {
-            #[cfg(windows)]
-            {
-                CmdOptions {
-                    detached,
-                    args: {
-                        match mode {
-                            ScriptMode::PowerShell => {
-                                let mut tmp = vec![
-                                    "-NonInteractive".as_ref(),
-                                    "-NoProfile".as_ref(),
-                                    "-ExecutionPolicy".as_ref(),
-                                    "Bypass".as_ref(),
-                                    temp_file_path.as_ref(),
-                                ];
-                                tmp.append(&mut args);
-                                tmp
-                            }
-                            ScriptMode::Binary { .. } => {
-                                let mut tmp = vec![temp_file_path.as_ref()];
-                                tmp.append(&mut args);
-                                tmp
-                            }
-                            ScriptMode::Cmd | ScriptMode::Directly => args,
-                        }
-                    },
-                    program: {
-                        match mode {
-                            ScriptMode::PowerShell => get_powershell_exe(),
-                            ScriptMode::Binary { path, .. } => path,
-                            ScriptMode::Cmd => get_cmd_exe(),
-                            ScriptMode::Directly => temp_file_path.to_path_buf(),
-                        }
-                    },
-                    env_vars: env_vars.into_iter().collect(),
-                    timeout,
-                }
-                .run()
-                .await
-            }
-            #[cfg(not(windows))]
-            {
-                match mode {
-                    ScriptMode::Binary { path, .. } => CmdOptions {
-                        detached,
-                        program: path,
-                        args: {
-                            let mut tmp = vec![temp_file_path.as_ref()];
-                            tmp.append(&mut args);
-                            tmp
-                        },
-                        env_vars: env_vars.into_iter().collect(),
-                        timeout,
-                    },
-                    _ => CmdOptions {
-                        detached,
-                        program: temp_file_path.to_path_buf(),
-                        args,
-                        env_vars: env_vars.into_iter().collect(),
-                        timeout,
-                    },
-                }
-                .run()
-                .await
+        let code = code.as_ref().trim();
+        let temp_file = crate::temp_file::TempFile::new(ext, code).await?;
+        let res = async {
+            let (program, mut script_args) = script_command(mode, temp_file.path(), code).await?;
+            script_args.extend(args.iter().map(|a| a.as_ref().to_os_string()));
+
+            CmdOptions {
+                detached,
+                program,
+                args: script_args,
+                env_vars: env_vars.into_iter().collect(),
+                timeout,
+                output,
+                job,
+                run_as_user,
+                stdin,
+                cwd,
+                max_output_bytes,
             }
-        };
+            .run()
+            .await
+        }
+        .await;
 
-        drop(temp_file);
+        if keep_script && res.is_ok() {
+            // The detached process outlives this call and still needs its script.
+            let _ = temp_file.keep();
+        } else {
+            drop(temp_file);
+        }
         res
     }
 }
 
+/// The program running a `mode` script stored at `script`, and its arguments up to the script's own.
+#[cfg_attr(windows, allow(unused_variables))]
+async fn script_command(
+    mode: ScriptMode,
+    script: &Path,
+    code: &str,
+) -> Result<(PathBuf, Vec<OsString>), Error> {
+    let script = script.as_os_str().to_os_string();
+
+    #[cfg(windows)]
+    let res = match mode {
+        ScriptMode::PowerShell => (
+            get_powershell_exe(),
+            vec![
+                "-NonInteractive".into(),
+                "-NoProfile".into(),
+                "-ExecutionPolicy".into(),
+                "Bypass".into(),
+                script,
+            ],
+        ),
+        ScriptMode::Pwsh => (
+            find_interpreter(&mode)?,
+            vec![
+                "-NonInteractive".into(),
+                "-NoProfile".into(),
+                "-ExecutionPolicy".into(),
+                "Bypass".into(),
+                "-File".into(),
+                script,
+            ],
+        ),
+        ScriptMode::Binary { path, .. } => (path, vec![script]),
+        ScriptMode::Cmd => (get_cmd_exe(), vec!["/C".into(), script]),
+        ScriptMode::Directly => (script.into(), vec![]),
+        ScriptMode::Bash | ScriptMode::Sh | ScriptMode::Python | ScriptMode::Perl => {
+            (find_interpreter(&mode)?, vec![script])
+        }
+    };
+
+    #[cfg(not(windows))]
+    let res = match mode {
+        ScriptMode::PowerShell | ScriptMode::Pwsh => (
+            find_interpreter(&ScriptMode::Pwsh)?,
+            vec![
+                "-NonInteractive".into(),
+                "-NoProfile".into(),
+                "-File".into(),
+                script,
+            ],
+        ),
+        ScriptMode::Binary { path, .. } => (path, vec![script]),
+        ScriptMode::Cmd => return Err(Error::UnsupportedShell("cmd".into())),
+        ScriptMode::Directly if code.starts_with("#!") => {
+            use std::os::unix::fs::PermissionsExt;
+            // Let the kernel follow the shebang, readable and executable for `run_as_user` too.
+            tokio::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).await?;
+            (script.into(), vec![])
+        }
+        ScriptMode::Directly => (find_interpreter(&ScriptMode::Sh)?, vec![script]),
+        ScriptMode::Bash | ScriptMode::Sh | ScriptMode::Python | ScriptMode::Perl => {
+            (find_interpreter(&mode)?, vec![script])
+        }
+    };
+
+    Ok(res)
+}
+
+/// Whether scripts in `mode` can run on this agent.
+pub fn script_mode_supported(mode: &ScriptMode) -> bool {
+    match mode {
+        ScriptMode::Binary { .. } | ScriptMode::Directly => true,
+        #[cfg(windows)]
+        ScriptMode::PowerShell | ScriptMode::Cmd => true,
+        #[cfg(not(windows))]
+        ScriptMode::PowerShell => find_interpreter(&ScriptMode::Pwsh).is_ok(),
+        #[cfg(not(windows))]
+        ScriptMode::Cmd => false,
+        _ => find_interpreter(mode).is_ok(),
+    }
+}
+
+/// Finds the interpreter of `mode` in `PATH`.
+fn find_interpreter(mode: &ScriptMode) -> Result<PathBuf, Error> {
+    let names: &[&str] = match mode {
+        ScriptMode::Bash => &["bash"],
+        ScriptMode::Sh => &["sh", "dash", "bash"],
+        #[cfg(windows)]
+        ScriptMode::Python => &["python", "py"],
+        #[cfg(not(windows))]
+        ScriptMode::Python => &["python3", "python"],
+        ScriptMode::Pwsh => &["pwsh"],
+        ScriptMode::Perl => &["perl"],
+        _ => &[],
+    };
+
+    names
+        .iter()
+        .find_map(|name| which(name))
+        .ok_or_else(|| Error::UnsupportedShell(names.first().unwrap_or(&"").to_string()))
+}
+
+/// Looks `name` up in the directories of `PATH`.
+fn which(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH");
+    let mut dirs = path
+        .as_ref()
+        .map(|path| std::env::split_paths(path).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    // Services may run with a minimal `PATH`.
+    #[cfg(not(windows))]
+    dirs.extend(["/usr/local/bin", "/usr/bin", "/bin"].map(PathBuf::from));
+
+    dirs.into_iter().find_map(|dir| {
+        #[cfg(windows)]
+        let candidate = dir.join(name).with_extension("exe");
+        #[cfg(not(windows))]
+        let candidate = dir.join(name);
+
+        candidate.is_file().then_some(candidate)
+    })
+}
+
 #[cfg(windows)]
 fn get_powershell_exe() -> std::path::PathBuf {
     use tracing::debug;
@@ -316,3 +1066,168 @@ fn get_cmd_exe() -> std::path::PathBuf {
 fn empty_vec() -> Vec<(&'static str, &'static str)> {
     vec![]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capped_buffer() {
+        let mut buf = CappedBuffer::new(6);
+        buf.push(b"ab");
+        assert!(!buf.truncated());
+        buf.push(b"cdef");
+        assert!(!buf.truncated());
+        buf.push(b"ghijkl");
+        buf.push(b"mn");
+        assert!(buf.truncated());
+        assert_eq!(buf.into_vec(), b"abclmn");
+
+        let mut buf = CappedBuffer::new(0);
+        buf.push(b"abc");
+        assert!(buf.truncated());
+        assert!(buf.into_vec().is_empty());
+    }
+
+    #[cfg(not(windows))]
+    async fn run_script(code: &str, mode: ScriptMode) -> Result<CmdOutput, Error> {
+        CmdScript::<_, &str, &str, &str> {
+            code,
+            mode,
+            args: vec![],
+            env_vars: HashMap::new(),
+            detached: None,
+            timeout: Duration::from_secs(10),
+            output: None,
+            job: None,
+            run_as_user: None,
+            stdin: None,
+            cwd: None,
+            max_output_bytes: None,
+        }
+        .run()
+        .await
+    }
+
+    #[cfg(not(windows))]
+    #[tokio::test]
+    async fn test_stdin_cwd() {
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+        let out = CmdShell {
+            shell: "sh",
+            command: "pwd; cat",
+            detached: None,
+            timeout: Duration::from_secs(10),
+            output: None,
+            job: None,
+            run_as_user: None,
+            stdin: Some(b"config blob".to_vec()),
+            cwd: Some(dir.clone()),
+            max_output_bytes: None,
+        }
+        .run()
+        .await
+        .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&out.stdout),
+            format!("{}\nconfig blob", dir.display())
+        );
+    }
+
+    #[cfg(not(windows))]
+    #[tokio::test]
+    async fn test_signal_termination() {
+        let out = run_script("kill -SEGV $$", ScriptMode::Sh).await.unwrap();
+        assert_eq!(out.signal(), Some(libc::SIGSEGV));
+        assert_eq!(out.signal_name().as_deref(), Some("SIGSEGV"));
+        assert_eq!(out.retcode(), 128 + libc::SIGSEGV);
+        assert_eq!(out.termination(), Termination::Signaled);
+
+        let out = run_script("exit 85", ScriptMode::Sh).await.unwrap();
+        assert_eq!(out.signal(), None);
+        assert_eq!(out.retcode(), 85);
+        assert_eq!(out.termination(), Termination::Exited);
+    }
+
+    #[cfg(not(windows))]
+    #[tokio::test]
+    async fn test_timeout_after_exit() {
+        // The child exits right away, a grandchild in its own session keeps stdout open.
+        let out = CmdShell {
+            shell: "sh",
+            command: "echo partial; setsid sleep 3 &",
+            detached: None,
+            timeout: Duration::from_millis(500),
+            output: None,
+            job: None,
+            run_as_user: None,
+            stdin: None,
+            cwd: None,
+            max_output_bytes: None,
+        }
+        .run()
+        .await
+        .unwrap();
+        assert!(out.timed_out);
+        assert!(out.status.success());
+        assert_eq!(out.stdout, b"partial\n");
+    }
+
+    #[cfg(not(windows))]
+    #[tokio::test]
+    async fn test_output_backpressure() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let run = CmdScript::<_, &str, &str, &str> {
+            code: "head -c 1000000 /dev/zero",
+            mode: ScriptMode::Sh,
+            args: vec![],
+            env_vars: HashMap::new(),
+            detached: None,
+            timeout: Duration::from_secs(10),
+            output: Some(sender),
+            job: None,
+            run_as_user: None,
+            stdin: None,
+            cwd: None,
+            max_output_bytes: None,
+        }
+        .run();
+        // A slow receiver, nothing gets lost while the command waits for it.
+        let receive = async {
+            let mut received = 0;
+            while let Some(chunk) = receiver.recv().await {
+                received += chunk.data.len();
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            received
+        };
+        let (out, received) = tokio::join!(run, receive);
+        assert!(out.unwrap().status.success());
+        assert_eq!(received, 1_000_000);
+    }
+
+    #[cfg(not(windows))]
+    #[tokio::test]
+    async fn test_script_modes() {
+        let out = run_script("echo $0", ScriptMode::Sh).await.unwrap();
+        assert!(String::from_utf8_lossy(&out.stdout).trim().ends_with(".sh"));
+
+        let out = run_script("#!/bin/sh\necho shebang", ScriptMode::Directly)
+            .await
+            .unwrap();
+        assert_eq!(out.stdout, b"shebang\n");
+
+        let out = run_script("echo no shebang", ScriptMode::Directly)
+            .await
+            .unwrap();
+        assert_eq!(out.stdout, b"no shebang\n");
+
+        assert!(matches!(
+            run_script("dir", ScriptMode::Cmd).await,
+            Err(Error::UnsupportedShell(_))
+        ));
+
+        assert!(which("sh").is_some());
+        assert!(which("ironhive-no-such-interpreter").is_none());
+    }
+}
@@ -1,5 +1,5 @@
-use shared::ScriptMode;
-use sysinfo::Pid;
+use shared::{ErrorCode, IronhiveError, ScriptMode};
+use sysinfo::{Pid, PidExt};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -62,4 +62,203 @@ pub enum Error {
     JoinError(#[from] tokio::task::JoinError),
     #[error("async nats error: {0}")]
     AsyncNatsError(#[from] async_nats::Error),
+    #[error("cron error: {0}")]
+    CronError(#[from] cron::error::Error),
+    #[error("schedule interval {0:?} is shorter than {1:?}")]
+    IntervalTooShort(std::time::Duration, std::time::Duration),
+    #[error("not found scheduled task: {0}")]
+    NotFoundSchedTask(String),
+    #[error("not found job: {0}")]
+    NotFoundJob(u64),
+    #[error("not found user: {0}")]
+    NotFoundUser(String),
+    #[error("base64 decode failed: {0}")]
+    Base64DecodeError(#[from] base64::DecodeError),
+    #[error("not an absolute path: {0:?}")]
+    NotAbsolutePath(std::path::PathBuf),
+    #[error("path not allowed: {0:?}")]
+    PathNotAllowed(std::path::PathBuf),
+    #[error("not found shell session: {0}")]
+    NotFoundShell(String),
+    #[error("not found tunnel: {0}")]
+    NotFoundTunnel(String),
+    #[error("tunnel target not allowed: {0}")]
+    TunnelTargetNotAllowed(String),
+    #[error("not found backup of: {0:?}")]
+    NotFoundBackup(std::path::PathBuf),
+    #[error("invalid upload id: {0}")]
+    InvalidUploadId(String),
+    #[error("not found upload: {0}")]
+    NotFoundUpload(String),
+    #[error("upload offset mismatch: expected {expected}, got {got}")]
+    UploadOffsetMismatch { expected: u64, got: u64 },
+    #[error("upload verify failed: {0}")]
+    UploadVerifyFailed(String),
+    #[error("{got} request sent to the {expected} endpoint")]
+    FuncMismatch { expected: String, got: String },
+    #[error("invalid subject prefix: {0:?}")]
+    InvalidSubjectPrefix(String),
+    #[error("invalid group: {0:?}")]
+    InvalidGroup(String),
+    #[error("too many requests, can't take {0} now")]
+    Busy(String),
+    #[error("acquire permit failed: {0}")]
+    AcquireError(#[from] tokio::sync::AcquireError),
+}
+
+impl Error {
+    /// The stable code clients see this error as.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::TokioIoError(e) => match e.kind() {
+                std::io::ErrorKind::NotFound => ErrorCode::NotFound,
+                std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+                std::io::ErrorKind::TimedOut => ErrorCode::Timeout,
+                std::io::ErrorKind::AlreadyExists => ErrorCode::Conflict,
+                std::io::ErrorKind::InvalidInput | std::io::ErrorKind::InvalidData => {
+                    ErrorCode::InvalidRequest
+                }
+                std::io::ErrorKind::ConnectionRefused
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::NotConnected
+                | std::io::ErrorKind::AddrNotAvailable
+                | std::io::ErrorKind::BrokenPipe => ErrorCode::Network,
+                _ => ErrorCode::Internal,
+            },
+            Error::Elapsed(_) => ErrorCode::Timeout,
+            Error::CmdError(_) | Error::RunScriptFailed(_) | Error::KillProcessFailed(_) => {
+                ErrorCode::CommandFailed
+            }
+            Error::NatsConnectError(_)
+            | Error::NatsSubscribeError(_)
+            | Error::NatsPublishError(_)
+            | Error::AsyncNatsError(_)
+            | Error::NotFoundPublicIp => ErrorCode::Network,
+            #[cfg(windows)]
+            Error::ReqwestError(_) => ErrorCode::Network,
+            Error::NotFoundProcess(_)
+            | Error::NotFoundSchedTask(_)
+            | Error::NotFoundJob(_)
+            | Error::NotFoundUser(_)
+            | Error::NotFoundShell(_)
+            | Error::NotFoundTunnel(_)
+            | Error::NotFoundBackup(_)
+            | Error::NotFoundUpload(_) => ErrorCode::NotFound,
+            Error::UnsupportedShell(_) | Error::UnsupportedRequest(_) => ErrorCode::Unsupported,
+            Error::SerdeJsonError(_)
+            | Error::FromUtf16Error(_)
+            | Error::FromUtf8Error(_)
+            | Error::CronError(_)
+            | Error::IntervalTooShort(..)
+            | Error::Base64DecodeError(_)
+            | Error::NotAbsolutePath(_)
+            | Error::InvalidUploadId(_)
+            | Error::PatternHasSeparator
+            | Error::FuncMismatch { .. }
+            | Error::InvalidSubjectPrefix(_)
+            | Error::InvalidGroup(_) => ErrorCode::InvalidRequest,
+            Error::PathNotAllowed(_) | Error::TunnelTargetNotAllowed(_) => {
+                ErrorCode::PermissionDenied
+            }
+            Error::UploadOffsetMismatch { .. } => ErrorCode::Conflict,
+            Error::UploadVerifyFailed(_) => ErrorCode::VerifyFailed,
+            Error::Busy(_) => ErrorCode::Busy,
+            Error::NotFoundNatsConnectOptions
+            | Error::NoReplySubject
+            | Error::SetupRpcFailed
+            | Error::BroadcastRecvError(_)
+            | Error::OneshotRecvError(_)
+            | Error::JoinError(_)
+            | Error::AcquireError(_) => ErrorCode::Internal,
+            #[cfg(windows)]
+            Error::WindowsError(_) | Error::WindowsServiceError(_) | Error::WmiError(_) => {
+                ErrorCode::Internal
+            }
+        }
+    }
+
+    /// Whether sending the same request again may succeed.
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self.code(),
+            ErrorCode::Timeout | ErrorCode::Network | ErrorCode::Conflict | ErrorCode::Busy
+        )
+    }
+}
+
+impl From<&Error> for IronhiveError {
+    fn from(err: &Error) -> Self {
+        let details = match err {
+            Error::UploadOffsetMismatch { expected, got } => {
+                Some(serde_json::json!({ "expected": expected, "got": got }))
+            }
+            Error::NotFoundProcess(pid) => Some(serde_json::json!({ "pid": pid.as_u32() })),
+            Error::NotFoundJob(id) => Some(serde_json::json!({ "job_id": id })),
+            Error::Busy(func) => Some(serde_json::json!({ "func": func })),
+            Error::NotFoundShell(id) => Some(serde_json::json!({ "session_id": id })),
+            Error::NotFoundTunnel(id) => Some(serde_json::json!({ "tunnel_id": id })),
+            Error::TunnelTargetNotAllowed(target) => Some(serde_json::json!({ "target": target })),
+            Error::NotFoundUpload(id) | Error::InvalidUploadId(id) => {
+                Some(serde_json::json!({ "upload_id": id }))
+            }
+            Error::NotAbsolutePath(path)
+            | Error::PathNotAllowed(path)
+            | Error::NotFoundBackup(path) => {
+                // `json!` panics on paths that aren't valid UTF-8.
+                Some(serde_json::json!({ "path": path.to_string_lossy() }))
+            }
+            _ => None,
+        };
+
+        IronhiveError {
+            code: err.code(),
+            message: err.to_string(),
+            retryable: err.retryable(),
+            details,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_payload() {
+        let err = IronhiveError::from(&Error::UploadOffsetMismatch {
+            expected: 8,
+            got: 14,
+        });
+        assert_eq!(err.code, ErrorCode::Conflict);
+        assert!(err.retryable);
+        assert_eq!(err.details.as_ref().unwrap()["expected"], 8);
+
+        let json = serde_json::to_value(&err).unwrap();
+        assert_eq!(json["code"], "conflict");
+        assert_eq!(
+            json["message"],
+            "upload offset mismatch: expected 8, got 14"
+        );
+
+        let err = IronhiveError::from(&Error::TokioIoError(std::io::Error::from(
+            std::io::ErrorKind::NotFound,
+        )));
+        assert_eq!(err.code, ErrorCode::NotFound);
+        assert!(!err.retryable);
+        assert_eq!(err.code.status(), 404);
+
+        let err = IronhiveError::from(&Error::UnsupportedRequest("WMI".into()));
+        assert_eq!(err.code, ErrorCode::Unsupported);
+        assert!(serde_json::to_value(&err).unwrap().get("details").is_none());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+
+            let path = std::ffi::OsStr::from_bytes(b"/not-\xffutf8");
+            let err = IronhiveError::from(&Error::PathNotAllowed(path.into()));
+            assert_eq!(err.details.unwrap()["path"], "/not-\u{fffd}utf8");
+        }
+    }
 }
@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    process::Output,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use shared::JobInfo;
+use tokio::sync::oneshot;
+
+use crate::error::Error;
+
+type CancelRequest = oneshot::Sender<Output>;
+
+struct Job {
+    info: JobInfo,
+    cancel: Option<oneshot::Sender<CancelRequest>>,
+}
+
+/// The table of running command executions.
+///
+/// Cloning is cheap, all clones share the same table.
+#[derive(Clone, Default)]
+pub struct Jobs {
+    next_id: Arc<AtomicU64>,
+    jobs: Arc<Mutex<HashMap<u64, Job>>>,
+}
+
+impl Jobs {
+    /// Adds a job described by `request`, it is removed when the returned handle is dropped.
+    pub fn register(&self, request: impl Into<String>) -> JobHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (cancel, cancel_rx) = oneshot::channel();
+
+        self.lock().insert(
+            id,
+            Job {
+                info: JobInfo {
+                    id,
+                    request: request.into(),
+                    pid: None,
+                    start_time: SystemTime::now(),
+                },
+                cancel: Some(cancel),
+            },
+        );
+
+        JobHandle {
+            id,
+            jobs: self.clone(),
+            cancel: cancel_rx,
+        }
+    }
+
+    pub fn list(&self) -> Vec<JobInfo> {
+        let mut jobs = self
+            .lock()
+            .values()
+            .map(|job| job.info.clone())
+            .collect::<Vec<_>>();
+        jobs.sort_by_key(|job| job.id);
+        jobs
+    }
+
+    /// Kills the process tree of job `id` and returns the output it produced so far.
+    pub async fn cancel(&self, id: u64) -> Result<Output, Error> {
+        let cancel = self
+            .lock()
+            .get_mut(&id)
+            .and_then(|job| job.cancel.take())
+            .ok_or(Error::NotFoundJob(id))?;
+
+        let (output, output_rx) = oneshot::channel();
+        cancel.send(output).map_err(|_| Error::NotFoundJob(id))?;
+
+        // The sender is dropped without a reply if the job finished on its own meanwhile.
+        output_rx.await.map_err(|_| Error::NotFoundJob(id))
+    }
+
+    /// Cancels every running job, their executions reply with the output produced so far.
+    pub async fn cancel_all(&self) {
+        let ids = self.lock().keys().copied().collect::<Vec<_>>();
+        futures::future::join_all(ids.into_iter().map(|id| self.cancel(id))).await;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Job>> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A registered job, owned by the command execution it describes.
+pub struct JobHandle {
+    id: u64,
+    jobs: Jobs,
+    cancel: oneshot::Receiver<CancelRequest>,
+}
+
+impl std::fmt::Debug for JobHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JobHandle").field("id", &self.id).finish()
+    }
+}
+
+impl JobHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn set_pid(&self, pid: Option<u32>) {
+        if let Some(job) = self.jobs.lock().get_mut(&self.id) {
+            job.info.pid = pid;
+        }
+    }
+
+    /// Resolves once the job is asked to be cancelled, yielding where to send the partial output.
+    pub(crate) async fn cancelled(&mut self) -> CancelRequest {
+        match (&mut self.cancel).await {
+            Ok(reply) => reply,
+            Err(_) => std::future::pending().await,
+        }
+    }
+}
+
+impl Drop for JobHandle {
+    fn drop(&mut self) {
+        self.jobs.lock().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_jobs() {
+        let jobs = Jobs::default();
+
+        let mut handle = jobs.register("sleep");
+        handle.set_pid(Some(42));
+
+        let list = jobs.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].request, "sleep");
+        assert_eq!(list[0].pid, Some(42));
+
+        let id = handle.id();
+        let (output, _) = tokio::join!(jobs.cancel(id), async {
+            let reply = handle.cancelled().await;
+            let _ = reply.send(Output {
+                status: Default::default(),
+                stdout: b"partial".to_vec(),
+                stderr: vec![],
+            });
+        });
+        assert_eq!(output.unwrap().stdout, b"partial");
+
+        assert!(matches!(jobs.cancel(id).await, Err(Error::NotFoundJob(_))));
+
+        drop(handle);
+        assert!(jobs.list().is_empty());
+    }
+}
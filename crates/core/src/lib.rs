@@ -2,15 +2,30 @@ mod agent;
 mod checkin;
 mod cmd;
 mod error;
+mod jobs;
+mod limits;
 mod rpc;
+mod sched;
+mod service;
+#[cfg(unix)]
+mod shell;
+mod shutdown;
+mod task;
 mod temp_file;
+mod transfer;
+mod tunnel;
+#[cfg(unix)]
+mod user;
 mod utils;
 #[cfg(windows)]
 mod windows;
 
 pub use agent::Agent;
 pub use error::Error;
+pub use limits::Limits;
 pub use rpc::Ironhive;
+pub use service::{REQUEST_ENDPOINT, SERVICE_NAME};
+pub use shutdown::{shutdown_signal, ShutdownHandle};
 
 pub use shared::*;
 
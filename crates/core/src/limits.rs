@@ -0,0 +1,171 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use shared::IronhiveRequest;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::warn;
+
+use crate::error::Error;
+
+/// Functions the global limit doesn't apply to, so a busy agent can still be checked on and
+/// its jobs, shells and tunnels be stopped.
+const CONTROL_FUNCTIONS: &[&str] = &[
+    "ping",
+    "capabilities",
+    "listjobs",
+    "canceljob",
+    "closeshell",
+    "closetunnel",
+];
+
+/// How many requests an agent handles at once.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Requests handled at once over all functions but a few control ones, unlimited if unset.
+    pub max_concurrent: Option<usize>,
+    /// Requests handled at once per function, by `func`.
+    pub max_concurrent_per_func: HashMap<String, usize>,
+    /// Requests waiting for their turn, more are replied to as busy right away.
+    pub max_queued: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_concurrent: None,
+            max_concurrent_per_func: Default::default(),
+            max_queued: 256,
+        }
+    }
+}
+
+/// Hands out permits to handle requests within [`Limits`].
+pub(crate) struct Limiter {
+    global: Option<Semaphore>,
+    funcs: HashMap<String, Semaphore>,
+    queued: AtomicUsize,
+    max_queued: usize,
+}
+
+/// Held while a request is handled.
+pub(crate) struct Permit<'l> {
+    _func: Option<SemaphorePermit<'l>>,
+    _global: Option<SemaphorePermit<'l>>,
+}
+
+impl Limiter {
+    pub fn new(limits: &Limits) -> Self {
+        let funcs = limits
+            .max_concurrent_per_func
+            .iter()
+            .filter(|(func, _)| {
+                let known = IronhiveRequest::FUNCTIONS.contains(&func.as_str());
+                if !known {
+                    warn!("ignore concurrency limit of unknown function: {func}");
+                }
+                known
+            })
+            .map(|(func, max)| (func.clone(), Semaphore::new((*max).max(1))))
+            .collect();
+
+        Self {
+            global: limits.max_concurrent.map(|max| Semaphore::new(max.max(1))),
+            funcs,
+            queued: AtomicUsize::new(0),
+            max_queued: limits.max_queued,
+        }
+    }
+
+    /// Waits for the turn of a `func` request, or fails with [`Error::Busy`] if too many
+    /// requests are waiting already.
+    pub async fn acquire(&self, func: &str) -> Result<Permit<'_>, Error> {
+        let func_limit = self.funcs.get(func);
+        let global_limit = self
+            .global
+            .as_ref()
+            .filter(|_| !CONTROL_FUNCTIONS.contains(&func));
+
+        let func_permit = func_limit.map(|limit| limit.try_acquire());
+        let global_permit = global_limit.map(|limit| limit.try_acquire());
+        if !matches!(func_permit, Some(Err(_))) && !matches!(global_permit, Some(Err(_))) {
+            return Ok(Permit {
+                _func: func_permit.and_then(Result::ok),
+                _global: global_permit.and_then(Result::ok),
+            });
+        }
+        drop((func_permit, global_permit));
+
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(Error::Busy(func.to_string()));
+        }
+        let _queued = Queued(&self.queued);
+
+        // The function's own limit first, so no global permit is held while waiting for it.
+        let func_permit = match func_limit {
+            Some(limit) => Some(limit.acquire().await?),
+            None => None,
+        };
+        let global_permit = match global_limit {
+            Some(limit) => Some(limit.acquire().await?),
+            None => None,
+        };
+        Ok(Permit {
+            _func: func_permit,
+            _global: global_permit,
+        })
+    }
+}
+
+/// Counts a request as waiting until dropped.
+struct Queued<'q>(&'q AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_limiter() {
+        let limiter = Limiter::new(&Limits {
+            max_concurrent: Some(1),
+            max_concurrent_per_func: HashMap::from([("rawcmd".into(), 2), ("nope".into(), 1)]),
+            max_queued: 1,
+        });
+        assert!(!limiter.funcs.contains_key("nope"));
+
+        let first = limiter.acquire("runscript").await.unwrap();
+
+        // Control requests get through a busy agent.
+        let ping = limiter.acquire("ping").await.unwrap();
+        drop(ping);
+
+        let queued = limiter.acquire("runscript");
+        tokio::pin!(queued);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut queued)
+            .await
+            .is_err());
+
+        // The queue is full.
+        assert!(matches!(
+            limiter.acquire("rawcmd").await,
+            Err(Error::Busy(func)) if func == "rawcmd"
+        ));
+
+        drop(first);
+        let second = queued.await.unwrap();
+        assert_eq!(limiter.queued.load(Ordering::SeqCst), 0);
+        drop(second);
+
+        let _third = limiter.acquire("rawcmd").await.unwrap();
+    }
+}
@@ -1,88 +1,142 @@
 use crate::agent::Agent;
 use crate::agent::{reboot_now, system_reboot_required};
-use crate::cmd::{CmdScript, CmdShell};
+use crate::cmd::{
+    CmdScript, CmdShell, Detached, OutputChunk, OutputSender, OUTPUT_CHANNEL_CAPACITY,
+};
 use crate::error::Error;
+use crate::jobs::Jobs;
+use crate::limits::Limiter;
+use crate::sched::Scheduler;
+use crate::service::{endpoint_name, ErrorStats, Inbound, REQUEST_ENDPOINT};
+#[cfg(unix)]
+use crate::shell::Shells;
+use crate::shutdown::ShutdownHandle;
+use crate::transfer::Uploads;
+use crate::tunnel::Tunnels;
+use crate::utils::encode;
 #[cfg(windows)]
 use crate::windows::wua::{get_win_updates, install_updates};
+use async_nats::service::{endpoint::Endpoint, Service};
 use async_nats::ConnectOptions;
+use bytes::Bytes;
 use futures_util::StreamExt;
 use sysinfo::PidExt;
-use tracing::{debug, error, trace};
+use tracing::{debug, error, info, trace, warn};
 
-use shared::{as_bytes, AgentMode, IronhiveRequest, IronhiveRespond};
+use shared::{
+    AgentMode, IronhiveRequest, IronhiveRespond, OutputStream, StreamChunkNats, AGENT_ID_HEADER,
+};
 
 pub struct Ironhive {
     pub client: async_nats::Client,
-    pub subscriber: async_nats::Subscriber,
+    /// The agent registered as a NATS micro-service, see [`crate::service`].
+    pub service: Service,
+    pub endpoints: Vec<Endpoint>,
+    /// Subscriptions to the subjects of the agent's groups.
+    pub groups: Vec<async_nats::Subscriber>,
     pub agent: Agent,
+    errors: ErrorStats,
+    shutdown: ShutdownHandle,
 }
 
+/// How long requests get to reply once their jobs were cancelled on shutdown.
+const CANCEL_GRACE: std::time::Duration = std::time::Duration::from_secs(5);
+
 struct NatsClient<'c> {
     client: &'c async_nats::Client,
+    agent: &'c Agent,
+    agent_subject: &'c str,
+    errors: &'c ErrorStats,
 }
 
 impl<'c> NatsClient<'c> {
-    fn new(client: &'c async_nats::Client) -> Self {
-        Self { client }
+    fn new(
+        client: &'c async_nats::Client,
+        agent: &'c Agent,
+        agent_subject: &'c str,
+        errors: &'c ErrorStats,
+    ) -> Self {
+        Self {
+            client,
+            agent,
+            agent_subject,
+            errors,
+        }
     }
 
-    /// Safaty: Ensure coverage of unit tests
-    async unsafe fn respond_raw<T: serde::Serialize>(
-        &self,
-        msg: async_nats::Message,
-        raw: &T,
-    ) -> Result<(), Error> {
-        if let Some(reply) = msg.reply {
-            self.client.publish(reply, as_bytes(raw)).await?;
-            Ok(())
-        } else {
-            Err(Error::NoReplySubject)
+    fn group_headers(&self) -> async_nats::HeaderMap {
+        let mut headers = async_nats::HeaderMap::new();
+        headers.insert(AGENT_ID_HEADER, self.agent.agent_id.as_str());
+        headers
+    }
+
+    async fn respond_payload(&self, msg: Inbound, payload: Bytes) -> Result<(), Error> {
+        match msg {
+            Inbound::Endpoint(request) if request.message.reply.is_some() => {
+                request.respond(Ok(payload)).await?;
+                Ok(())
+            }
+            Inbound::Group(async_nats::Message {
+                reply: Some(reply), ..
+            }) => {
+                self.client
+                    .publish_with_headers(reply, self.group_headers(), payload)
+                    .await?;
+                Ok(())
+            }
+            _ => Err(Error::NoReplySubject),
         }
     }
 
-    async fn respond(&self, msg: async_nats::Message, resp: &IronhiveRespond) -> Result<(), Error> {
-        unsafe { self.respond_raw(msg, resp) }.await
+    async fn respond(&self, msg: Inbound, resp: &IronhiveRespond) -> Result<(), Error> {
+        self.respond_res_raw(msg, &Ok(resp)).await
     }
 
-    /// Safaty: Ensure coverage of unit tests
-    async unsafe fn respond_res_raw<T: serde::Serialize>(
+    /// Replies with `raw_res`, or with the error of serializing it if that fails.
+    async fn respond_res_raw<T: serde::Serialize>(
         &self,
-        msg: async_nats::Message,
+        msg: Inbound,
         raw_res: &Result<T, Error>,
     ) -> Result<(), Error> {
-        match raw_res {
-            Ok(raw) => {
-                if let Some(reply) = msg.reply {
-                    self.client.publish(reply, as_bytes(raw)).await
-                } else {
-                    return Err(Error::NoReplySubject);
-                }
-            }
-            Err(err) => {
-                let mut headers = async_nats::HeaderMap::new();
-                headers.insert(
-                    async_nats::service::NATS_SERVICE_ERROR,
-                    format!("{err:?}").as_str(),
-                );
-                if let Some(reply) = msg.reply {
-                    self.client
-                        .publish_with_headers(reply, headers, "".into())
-                        .await
-                } else {
-                    return Err(Error::NoReplySubject);
-                }
-            }
-        }?;
+        let err = match raw_res.as_ref().map(serde_json::to_vec) {
+            Ok(Ok(payload)) => return self.respond_payload(msg, payload.into()).await,
+            Ok(Err(e)) => shared::IronhiveError::from(&Error::from(e)),
+            Err(err) => shared::IronhiveError::from(err),
+        };
+
+        let mut headers = match &msg {
+            Inbound::Endpoint(_) => async_nats::HeaderMap::new(),
+            Inbound::Group(_) => self.group_headers(),
+        };
+        headers.insert(
+            async_nats::service::NATS_SERVICE_ERROR,
+            err.message.as_str(),
+        );
+        headers.insert(
+            async_nats::service::NATS_SERVICE_ERROR_CODE,
+            err.code.status().to_string().as_str(),
+        );
+
+        let reply = msg.message().reply.clone().ok_or(Error::NoReplySubject)?;
+        if let Inbound::Endpoint(request) = &msg {
+            self.errors.record(
+                endpoint_name(self.agent_subject, &request.message.subject),
+                &err,
+            );
+        }
+        self.client
+            .publish_with_headers(reply, headers, serde_json::to_vec(&err)?.into())
+            .await?;
 
         Ok(())
     }
 
     async fn respond_res(
         &self,
-        msg: async_nats::Message,
+        msg: Inbound,
         resp: &Result<IronhiveRespond, Error>,
     ) -> Result<(), Error> {
-        unsafe { self.respond_res_raw(msg, resp) }.await?;
+        self.respond_res_raw(msg, resp).await?;
 
         Ok(())
     }
@@ -92,96 +146,221 @@ impl Ironhive {
     pub async fn new(agent: Agent) -> Result<Self, Error> {
         let client = async_nats::connect(&agent.nats_servers).await?;
 
-        let subscriber = client.subscribe(agent.agent_id.clone()).await?;
-
-        Ok(Self {
-            client,
-            subscriber,
-            agent,
-        })
+        Self::start(client, agent).await
     }
 
     pub async fn new_with_options(agent: Agent, options: ConnectOptions) -> Result<Self, Error> {
         let client = async_nats::connect_with_options(&agent.nats_servers, options).await?;
 
-        let subscriber = client.subscribe(agent.agent_id.clone()).await?;
+        Self::start(client, agent).await
+    }
+
+    async fn start(client: async_nats::Client, agent: Agent) -> Result<Self, Error> {
+        let (service, endpoints, errors) = crate::service::start(&client, &agent).await?;
+
+        let groups = crate::service::subscribe_groups(&client, &agent).await?;
 
         Ok(Self {
             client,
-            subscriber,
+            service,
+            endpoints,
+            groups,
             agent,
+            errors,
+            shutdown: Default::default(),
         })
     }
 
+    /// A handle to shut the agent down gracefully once it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
     pub async fn run(self) -> Result<(), Error> {
         trace!("start run.");
         let Self {
             client,
-            mut subscriber,
+            service,
+            endpoints,
+            groups,
             agent,
+            errors,
+            shutdown,
         } = self;
 
+        let agent_subject = agent.subject();
+
+        let mut requests = futures_util::stream::select(
+            futures_util::stream::select_all(endpoints).map(Inbound::Endpoint),
+            futures_util::stream::select_all(groups).map(Inbound::Group),
+        );
+
         #[cfg(windows)]
         let wmi = crate::windows::wmi::WmiManager::init().await?;
 
         #[cfg(windows)]
         let get_win_update_locker = tokio::sync::Mutex::new(());
+
+        let scheduler = Scheduler::load(agent.sched_tasks_path()).await?;
+
+        let jobs = Jobs::default();
+
+        let uploads = Uploads::default();
+
+        #[cfg(unix)]
+        let shells = Shells::default();
+
+        let tunnels = Tunnels::default();
+
+        let limiter = Limiter::new(&agent.limits);
+
         // TODO: safaty comments
         let mut scope = unsafe { async_scoped::TokioScope::create() };
 
         debug!("start handle NATS message.");
 
-        while let Some(msg) = subscriber.next().await {
-            trace!("recv nats message: {:#?}", &msg);
+        let handle_messages = async {
+            while let Some(msg) = requests.next().await {
+                trace!("recv nats message: {:#?}", msg.message());
 
-            let agent = &agent;
-            let client = &client;
-            let nats_client = NatsClient::new(client);
-            #[cfg(windows)]
-            let wua_locker = &get_win_update_locker;
-            #[cfg(windows)]
-            let wmi = &wmi;
-            scope.spawn(async move {
-                if let Ok(nats_msg) = serde_json::from_slice::<'_, IronhiveRequest>(&msg.payload) {
-                    debug!("recv nats msg: {:?}", &nats_msg);
-                    handle_request(
-                        nats_msg,
-                        nats_client,
-                        msg,
-                        agent,
-                        client,
-                        #[cfg(windows)]
-                        wmi,
-                        #[cfg(windows)]
-                        wua_locker,
-                    )
-                    .await;
+                let agent = &agent;
+                let client = &client;
+                let scheduler = &scheduler;
+                let jobs = &jobs;
+                let uploads = &uploads;
+                #[cfg(unix)]
+                let shells = &shells;
+                let tunnels = &tunnels;
+                let agent_subject = &agent_subject;
+                let limiter = &limiter;
+                let nats_client = NatsClient::new(client, agent, agent_subject, &errors);
+                #[cfg(windows)]
+                let wua_locker = &get_win_update_locker;
+                #[cfg(windows)]
+                let wmi = &wmi;
+                scope.spawn(async move {
+                    let endpoint = match &msg {
+                        Inbound::Endpoint(request) => {
+                            endpoint_name(agent_subject, &request.message.subject)
+                        }
+                        Inbound::Group(_) => REQUEST_ENDPOINT,
+                    };
+                    match crate::service::parse_request(endpoint, &msg.message().payload) {
+                        Ok(nats_msg) => {
+                            debug!("recv nats msg: {:?}", &nats_msg);
+                            let _permit = match limiter.acquire(nats_msg.func()).await {
+                                Ok(permit) => permit,
+                                Err(e) => {
+                                    let res = Err(e);
+                                    if let Err(e) = nats_client.respond_res(msg, &res).await {
+                                        error!("Reply busy failed: {e:?}");
+                                    }
+                                    return;
+                                }
+                            };
+                            handle_request(
+                                nats_msg,
+                                nats_client,
+                                msg,
+                                agent,
+                                client,
+                                scheduler,
+                                jobs,
+                                uploads,
+                                #[cfg(unix)]
+                                shells,
+                                tunnels,
+                                #[cfg(windows)]
+                                wmi,
+                                #[cfg(windows)]
+                                wua_locker,
+                            )
+                            .await;
 
-                    if let Err(e) = client.flush().await {
-                        error!("Flush NATS client failed: {e:?}");
+                            if let Err(e) = client.flush().await {
+                                error!("Flush NATS client failed: {e:?}");
+                            }
+                        }
+                        Err(e) if endpoint != REQUEST_ENDPOINT => {
+                            let res = Err(e);
+                            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                                error!("Reply invalid request failed: {e:?}");
+                            }
+                        }
+                        Err(_) => trace!("Unknow request: {:?}", msg.message()),
                     }
-                } else {
-                    trace!("Unknow request: {:?}", msg);
-                }
-            });
+                });
+            }
+        };
+
+        let log_dir = agent.detached_log_dir();
+        tokio::select! {
+            _ = handle_messages => {},
+            _ = scheduler.run(&agent.agent_id, &agent.sched_task_subject, &log_dir, &client, &jobs) => {},
+            _ = shutdown.wait() => info!("shutting down."),
+        }
+
+        // Take no more requests.
+        drop(requests);
+        if let Err(e) = service.stop().await {
+            error!("Stop service failed: {e:?}");
         }
 
-        while let Some(handle) = scope.next().await {
-            if let Err(e) = handle {
-                error!("join handle failed: {e:?}");
+        #[cfg(unix)]
+        shells.close_all().await;
+        tunnels.close_all().await;
+
+        let drain = async {
+            while let Some(handle) = scope.next().await {
+                if let Err(e) = handle {
+                    error!("join handle failed: {e:?}");
+                }
             }
+            scheduler.drain().await;
+        };
+        let deadline = async {
+            tokio::time::sleep(agent.shutdown_timeout).await;
+            warn!(
+                "requests or scheduled tasks still running after {:?}, cancel their jobs.",
+                agent.shutdown_timeout
+            );
+            jobs.cancel_all().await;
+            tokio::time::sleep(CANCEL_GRACE).await;
+        };
+        let drained = tokio::select! {
+            _ = drain => true,
+            _ = deadline => false,
+        };
+        if !drained {
+            warn!("abort requests still running.");
+            scope.cancel();
+            while scope.next().await.is_some() {}
+            scheduler.abort_all().await;
+        }
+
+        if let Err(e) = agent.offline_message(&client).await {
+            error!("Publish offline message failed: {e:?}");
+        }
+        if let Err(e) = client.flush().await {
+            error!("Flush NATS client failed: {e:?}");
         }
 
         Ok(())
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_request(
     nats_msg: IronhiveRequest,
     nats_client: NatsClient<'_>,
-    msg: async_nats::Message,
+    msg: Inbound,
     agent: &Agent,
     client: &async_nats::Client,
+    scheduler: &Scheduler,
+    jobs: &Jobs,
+    uploads: &Uploads,
+    #[cfg(unix)] shells: &Shells,
+    tunnels: &Tunnels,
     #[cfg(windows)] wmi: &crate::windows::wmi::WmiManager,
     #[cfg(windows)] wua_locker: &tokio::sync::Mutex<()>,
 ) {
@@ -191,6 +370,24 @@ async fn handle_request(
                 error!("Publish pong failed: {e:?}");
             }
         }
+        IronhiveRequest::Capabilities => {
+            if let Err(e) = nats_client
+                .respond(
+                    msg,
+                    &IronhiveRespond::Capabilities {
+                        version: agent.version().to_string(),
+                        os: std::env::consts::OS.to_string(),
+                        arch: std::env::consts::ARCH.to_string(),
+                        protocol_version: shared::PROTOCOL_VERSION,
+                        functions: crate::agent::supported_functions(),
+                        script_modes: crate::agent::supported_script_modes(),
+                    },
+                )
+                .await
+            {
+                error!("Capabilities failed: {e:?}");
+            }
+        }
         IronhiveRequest::Procs => {
             if let Err(e) = nats_client
                 .respond(
@@ -221,31 +418,69 @@ async fn handle_request(
             shell,
             command,
             timeout,
-            // run_as_user,
+            run_as_user,
+            stream_subject,
+            detached,
+            stdin,
+            cwd,
+            structured,
+            max_output_bytes,
+            encoding,
         } => {
-            let cmd_shell = CmdShell {
-                shell,
-                command,
-                detached: false,
-                timeout,
-            };
+            let now = std::time::Instant::now();
+            // A detached command is not waited for, so there is no job to track.
+            let job = (!detached).then(|| jobs.register(format!("rawcmd: {command}")));
+            let res = stream_output(client, stream_subject, |output| async move {
+                CmdShell {
+                    shell,
+                    command,
+                    detached: detached.then(|| agent.detached_log_dir()),
+                    timeout,
+                    output,
+                    job,
+                    run_as_user,
+                    stdin: stdin.map(crate::utils::decode_stdin).transpose()?,
+                    cwd,
+                    max_output_bytes,
+                }
+                .run()
+                .await
+            })
+            .await;
+            let execution_time = std::time::Instant::now() - now;
 
             if let Err(e) = nats_client
                 .respond_res(
                     msg,
-                    &cmd_shell
-                        .run()
-                        .await
-                        .map(|resp| IronhiveRespond::RawCMDResp {
-                            results: {
-                                String::from_utf8_lossy(&if resp.stderr.is_empty() {
-                                    resp.stdout
+                    &res.map(|resp| match resp.detached {
+                        Some(detached) => detached_resp(detached),
+                        None if structured => IronhiveRespond::RawCMDOutput {
+                            stdout: encode(&resp.stdout, encoding),
+                            stderr: encode(&resp.stderr, encoding),
+                            retcode: resp.retcode(),
+                            signal: resp.signal(),
+                            signal_name: resp.signal_name(),
+                            core_dumped: resp.core_dumped(),
+                            termination: resp.termination(),
+                            execution_time,
+                            timed_out: resp.timed_out,
+                            truncated: resp.truncated,
+                            encoding,
+                        },
+                        None => IronhiveRespond::RawCMDResp {
+                            results: encode(
+                                if resp.stderr.is_empty() {
+                                    &resp.stdout
                                 } else {
-                                    resp.stderr
-                                })
-                                .to_string()
-                            },
-                        }),
+                                    &resp.stderr
+                                },
+                                encoding,
+                            ),
+                            timed_out: resp.timed_out,
+                            truncated: resp.truncated,
+                            encoding,
+                        },
+                    }),
                 )
                 .await
             {
@@ -257,32 +492,59 @@ async fn handle_request(
             mode,
             script_args,
             timeout,
-            // run_as_user,
+            run_as_user,
             env_vars,
             id,
+            stream_subject,
+            detached,
+            stdin,
+            cwd,
+            max_output_bytes,
+            encoding,
         } => {
             let now = std::time::Instant::now();
-            let cmd_script = CmdScript {
-                code,
-                mode,
-                args: script_args,
-                env_vars,
-                detached: false,
-                timeout,
-            };
-            let res = cmd_script.run().await;
+            let job = (!detached).then(|| jobs.register(format!("runscript: {id}")));
+            let res = stream_output(client, stream_subject, |output| async move {
+                CmdScript {
+                    code,
+                    mode,
+                    args: script_args,
+                    env_vars,
+                    detached: detached.then(|| agent.detached_log_dir()),
+                    timeout,
+                    output,
+                    job,
+                    run_as_user,
+                    stdin: stdin.map(crate::utils::decode_stdin).transpose()?,
+                    cwd,
+                    max_output_bytes,
+                }
+                .run()
+                .await
+            })
+            .await;
             debug!("{res:#?}");
             let execution_time = std::time::Instant::now() - now;
 
             if let Err(e) = nats_client
                 .respond_res(
                     msg,
-                    &res.map(|resp| IronhiveRespond::RunScriptResp {
-                        stdout: String::from_utf8_lossy(&resp.stdout).to_string(),
-                        stderr: String::from_utf8_lossy(&resp.stderr).to_string(),
-                        retcode: resp.status.code().unwrap_or(85),
-                        execution_time,
-                        id,
+                    &res.map(|resp| match resp.detached {
+                        Some(detached) => detached_resp(detached),
+                        None => IronhiveRespond::RunScriptResp {
+                            stdout: encode(&resp.stdout, encoding),
+                            stderr: encode(&resp.stderr, encoding),
+                            retcode: resp.retcode(),
+                            signal: resp.signal(),
+                            signal_name: resp.signal_name(),
+                            core_dumped: resp.core_dumped(),
+                            termination: resp.termination(),
+                            execution_time,
+                            id,
+                            timed_out: resp.timed_out,
+                            truncated: resp.truncated,
+                            encoding,
+                        },
                     }),
                 )
                 .await
@@ -290,6 +552,49 @@ async fn handle_request(
                 error!("Raw command failed: {e:?}");
             }
         }
+        IronhiveRequest::RunTask { task } => {
+            let id = task.id;
+            let results = crate::task::run_task(task, jobs, &agent.detached_log_dir()).await;
+
+            if let Err(e) = nats_client
+                .respond(msg, &IronhiveRespond::RunTaskResp { id, results })
+                .await
+            {
+                error!("Run task failed: {e:?}");
+            }
+        }
+        IronhiveRequest::SchedTask { task } => {
+            if let Err(e) = nats_client
+                .respond_res(msg, &scheduler.add(task).await.map(|_| IronhiveRespond::Ok))
+                .await
+            {
+                error!("SchedTask failed: {e:?}");
+            }
+        }
+        IronhiveRequest::DelSchedTask { name } => {
+            if let Err(e) = nats_client
+                .respond_res(
+                    msg,
+                    &scheduler.remove(&name).await.map(|_| IronhiveRespond::Ok),
+                )
+                .await
+            {
+                error!("DelSchedTask failed: {e:?}");
+            }
+        }
+        IronhiveRequest::ListSchedTasks => {
+            if let Err(e) = nats_client
+                .respond(
+                    msg,
+                    &IronhiveRespond::SchedTasks {
+                        tasks: scheduler.list().await,
+                    },
+                )
+                .await
+            {
+                error!("ListSchedTasks failed: {e:?}");
+            }
+        }
         IronhiveRequest::RebootNow => {
             if let Err(e) = nats_client
                 .respond_res(msg, &reboot_now().await.map(|_| IronhiveRespond::Ok))
@@ -398,6 +703,304 @@ async fn handle_request(
                 error!("Get public ip failed: {e:?}");
             }
         }
+        IronhiveRequest::ListJobs => {
+            if let Err(e) = nats_client
+                .respond(msg, &IronhiveRespond::Jobs { jobs: jobs.list() })
+                .await
+            {
+                error!("ListJobs failed: {e:?}");
+            }
+        }
+        IronhiveRequest::CancelJob { id } => {
+            let res = jobs
+                .cancel(id)
+                .await
+                .map(|output| IronhiveRespond::JobCancelled {
+                    id,
+                    stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+                    stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+                });
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("CancelJob failed: {e:?}");
+            }
+        }
+        IronhiveRequest::ListDir {
+            path,
+            offset,
+            limit,
+        } => {
+            let res = agent
+                .list_dir(&path, offset, limit)
+                .await
+                .map(|(entries, total)| {
+                    let next = offset.saturating_add(limit);
+                    IronhiveRespond::DirEntries {
+                        path,
+                        entries,
+                        total,
+                        next_offset: (next < total).then_some(next),
+                    }
+                });
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("ListDir failed: {e:?}");
+            }
+        }
+        IronhiveRequest::Stat { path } => {
+            let res = agent
+                .stat(&path)
+                .await
+                .map(|entry| IronhiveRespond::FileStat { path, entry });
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("Stat failed: {e:?}");
+            }
+        }
+        IronhiveRequest::ReadFileRange {
+            path,
+            offset,
+            len,
+            encoding,
+        } => {
+            let res = agent
+                .read_file_range(&path, offset, len)
+                .await
+                .map(|(data, size)| IronhiveRespond::FileRange {
+                    eof: offset + data.len() as u64 >= size,
+                    data: encode(&data, encoding),
+                    path,
+                    offset,
+                    encoding,
+                    size,
+                });
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("ReadFileRange failed: {e:?}");
+            }
+        }
+        #[allow(unused_variables)]
+        IronhiveRequest::OpenShell {
+            shell,
+            cols,
+            rows,
+            idle_timeout,
+        } => {
+            #[cfg(unix)]
+            let res = shells
+                .open(client, &agent.subject(), shell, cols, rows, idle_timeout)
+                .await
+                .map(|(session_id, pid, subjects)| IronhiveRespond::ShellOpened {
+                    session_id,
+                    pid,
+                    input_subject: subjects.input,
+                    output_subject: subjects.output,
+                    closed_subject: subjects.closed,
+                });
+            #[cfg(not(unix))]
+            let res = Err(Error::UnsupportedRequest("OpenShell".into()));
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("OpenShell failed: {e:?}");
+            }
+        }
+        #[allow(unused_variables)]
+        IronhiveRequest::ResizeShell {
+            session_id,
+            cols,
+            rows,
+        } => {
+            #[cfg(unix)]
+            let res = shells
+                .resize(&session_id, cols, rows)
+                .map(|_| IronhiveRespond::Ok);
+            #[cfg(not(unix))]
+            let res = Err(Error::UnsupportedRequest("ResizeShell".into()));
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("ResizeShell failed: {e:?}");
+            }
+        }
+        #[allow(unused_variables)]
+        IronhiveRequest::CloseShell { session_id } => {
+            #[cfg(unix)]
+            let res = shells.close(&session_id).map(|_| IronhiveRespond::Ok);
+            #[cfg(not(unix))]
+            let res = Err(Error::UnsupportedRequest("CloseShell".into()));
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("CloseShell failed: {e:?}");
+            }
+        }
+        IronhiveRequest::OpenTunnel {
+            target_host,
+            target_port,
+            idle_timeout,
+        } => {
+            let res = async {
+                agent.check_tunnel_target(&target_host, target_port)?;
+                tunnels
+                    .open(
+                        client,
+                        &agent.subject(),
+                        &target_host,
+                        target_port,
+                        idle_timeout,
+                    )
+                    .await
+                    .map(|(tunnel_id, subjects)| IronhiveRespond::TunnelOpened {
+                        tunnel_id,
+                        input_subject: subjects.input,
+                        output_subject: subjects.output,
+                        closed_subject: subjects.closed,
+                    })
+            }
+            .await;
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("OpenTunnel failed: {e:?}");
+            }
+        }
+        IronhiveRequest::CloseTunnel { tunnel_id } => {
+            let res = tunnels.close(&tunnel_id).map(|_| IronhiveRespond::Ok);
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("CloseTunnel failed: {e:?}");
+            }
+        }
+        IronhiveRequest::WriteFile {
+            path,
+            content,
+            encoding,
+            mode,
+            backup,
+        } => {
+            let res = async {
+                let content = crate::utils::decode(&content, encoding)?;
+                agent.write_file(&path, &content, mode, backup).await
+            }
+            .await
+            .map(|write| IronhiveRespond::FileWritten {
+                path,
+                size: write.size,
+                before_sha256: write.before_sha256,
+                after_sha256: write.after_sha256,
+                backup_path: write.backup_path,
+            });
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("WriteFile failed: {e:?}");
+            }
+        }
+        IronhiveRequest::RestoreBackup { path, backup_path } => {
+            let res = agent
+                .restore_backup(&path, backup_path)
+                .await
+                .map(|(backup_path, write)| IronhiveRespond::BackupRestored {
+                    path,
+                    backup_path,
+                    before_sha256: write.before_sha256,
+                    after_sha256: write.after_sha256,
+                });
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("RestoreBackup failed: {e:?}");
+            }
+        }
+        IronhiveRequest::UploadStart {
+            upload_id,
+            path,
+            size,
+            sha256,
+            mode,
+            owner,
+        } => {
+            let res = async {
+                agent.check_path_allowed(&path)?;
+                uploads
+                    .start(upload_id.clone(), path, size, sha256, mode, owner)
+                    .await
+            }
+            .await
+            .map(|received| IronhiveRespond::UploadStatus {
+                upload_id,
+                received,
+            });
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("UploadStart failed: {e:?}");
+            }
+        }
+        IronhiveRequest::UploadChunk {
+            upload_id,
+            offset,
+            data,
+        } => {
+            let res = async {
+                let data = crate::utils::decode(&data, shared::Encoding::Base64)?;
+                uploads.chunk(&upload_id, offset, &data).await
+            }
+            .await
+            .map(|received| IronhiveRespond::UploadStatus {
+                upload_id,
+                received,
+            });
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("UploadChunk failed: {e:?}");
+            }
+        }
+        IronhiveRequest::UploadFinish { upload_id } => {
+            let res = uploads
+                .finish(&upload_id)
+                .await
+                .map(|(path, size)| IronhiveRespond::UploadDone { path, size });
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("UploadFinish failed: {e:?}");
+            }
+        }
+        IronhiveRequest::DownloadFile {
+            path,
+            subject,
+            offset,
+            max_len,
+        } => {
+            let res = async {
+                agent.check_path_allowed(&path)?;
+                crate::transfer::download(&path, offset, max_len, |chunk| {
+                    let subject = subject.clone();
+                    async move {
+                        let seq = chunk.seq;
+                        client
+                            .publish(subject, serde_json::to_vec(&chunk)?.into())
+                            .await?;
+                        // Don't let a large file pile up in the client's buffers.
+                        if seq % 16 == 15 {
+                            client
+                                .flush()
+                                .await
+                                .map_err(|e| Error::AsyncNatsError(e.into()))?;
+                        }
+                        Ok(())
+                    }
+                })
+                .await
+            }
+            .await
+            .map(|download| IronhiveRespond::FileDownloaded {
+                path,
+                size: download.size,
+                offset,
+                len: download.len,
+                sha256: download.sha256,
+                chunks: download.chunks,
+            });
+
+            if let Err(e) = nats_client.respond_res(msg, &res).await {
+                error!("DownloadFile failed: {e:?}");
+            }
+        }
         IronhiveRequest::Checkin { mode } => {
             if let Err(e) = nats_client
                 .respond_res(
@@ -441,19 +1044,33 @@ async fn handle_request(
             }
         }
         #[allow(unused_variables)]
-        IronhiveRequest::InstallWithChoco { choco_prog_name } => {
+        IronhiveRequest::InstallWithChoco {
+            choco_prog_name,
+            stream_subject,
+        } => {
             #[cfg(windows)]
             let res = {
                 let now = std::time::Instant::now();
-                let res = crate::windows::choco::install_with_choco(choco_prog_name).await;
+                let job = Some(jobs.register(format!("choco install {choco_prog_name}")));
+                let res = stream_output(client, stream_subject, |output| {
+                    crate::windows::choco::install_with_choco(choco_prog_name, output, job)
+                })
+                .await;
                 debug!("{res:#?}");
                 let execution_time = std::time::Instant::now() - now;
                 res.map(|resp| IronhiveRespond::RunScriptResp {
                     stdout: String::from_utf8_lossy(&resp.stdout).to_string(),
                     stderr: String::from_utf8_lossy(&resp.stderr).to_string(),
-                    retcode: resp.status.code().unwrap_or(85),
+                    retcode: resp.retcode(),
+                    signal: resp.signal(),
+                    signal_name: resp.signal_name(),
+                    core_dumped: resp.core_dumped(),
+                    termination: resp.termination(),
                     execution_time,
                     id: -1,
+                    timed_out: resp.timed_out,
+                    truncated: resp.truncated,
+                    encoding: shared::Encoding::Utf8,
                 })
             };
 
@@ -607,3 +1224,91 @@ async fn handle_request(
         }
     }
 }
+
+fn detached_resp(detached: Detached) -> IronhiveRespond {
+    let Detached {
+        pid,
+        stdout_path,
+        stderr_path,
+    } = detached;
+    IronhiveRespond::Detached {
+        pid,
+        stdout_path,
+        stderr_path,
+    }
+}
+
+/// Runs `run`, publishing the output it sends to `subject` as [`StreamChunkNats`] if a subject is given.
+///
+/// All chunks are published before this returns, so they always arrive before the final reply.
+async fn stream_output<F, Fut, T>(client: &async_nats::Client, subject: Option<String>, run: F) -> T
+where
+    F: FnOnce(Option<OutputSender>) -> Fut,
+    Fut: std::future::Future<Output = T>,
+{
+    let Some(subject) = subject else {
+        return run(None).await;
+    };
+
+    let (sender, mut receiver) = tokio::sync::mpsc::channel::<OutputChunk>(OUTPUT_CHANNEL_CAPACITY);
+
+    let publish = async {
+        let mut seq = 0;
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+
+        let mut publish_chunk = |stream: OutputStream, data: String| {
+            let chunk = StreamChunkNats { seq, stream, data };
+            seq += 1;
+            let subject = subject.clone();
+            async move {
+                let res = async {
+                    let payload = serde_json::to_vec(&chunk)?;
+                    client.publish(subject, payload.into()).await?;
+                    Result::<(), Error>::Ok(())
+                };
+                if let Err(e) = res.await {
+                    error!("Publish output chunk failed: {e:?}");
+                }
+            }
+        };
+
+        while let Some(OutputChunk { stream, data }) = receiver.recv().await {
+            let buf = match stream {
+                OutputStream::Stdout => &mut stdout,
+                OutputStream::Stderr => &mut stderr,
+            };
+            buf.extend_from_slice(&data);
+            let data = take_utf8(buf);
+            if !data.is_empty() {
+                publish_chunk(stream, data).await;
+            }
+        }
+
+        for (stream, buf) in [
+            (OutputStream::Stdout, stdout),
+            (OutputStream::Stderr, stderr),
+        ] {
+            if !buf.is_empty() {
+                publish_chunk(stream, String::from_utf8_lossy(&buf).to_string()).await;
+            }
+        }
+    };
+
+    let (res, _) = tokio::join!(run(Some(sender)), publish);
+
+    res
+}
+
+/// Takes the longest prefix of `buf` that can be decoded, keeping a trailing
+/// incomplete utf-8 character for the next chunk.
+fn take_utf8(buf: &mut Vec<u8>) -> String {
+    let valid = match std::str::from_utf8(buf) {
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        _ => buf.len(),
+    };
+    let rest = buf.split_off(valid);
+    let data = String::from_utf8_lossy(buf).to_string();
+    *buf = rest;
+    data
+}
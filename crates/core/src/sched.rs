@@ -0,0 +1,374 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use shared::{SchedTaskResultNats, Schedule, ScheduledTask};
+use tokio::{
+    sync::{Mutex, Notify},
+    task::JoinHandle,
+};
+use tracing::{debug, error, trace};
+
+use crate::{error::Error, jobs::Jobs};
+
+/// Interval tasks can't run more often than this.
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Agent side scheduler of [`ScheduledTask`]s.
+///
+/// Tasks are persisted as json to `path`, so they survive agent restarts.
+pub struct Scheduler {
+    path: PathBuf,
+    tasks: Mutex<Vec<ScheduledTask>>,
+    changed: Notify,
+    /// Runs in progress, by task name.
+    running: Mutex<HashMap<String, JoinHandle<()>>>,
+}
+
+impl Scheduler {
+    /// Loads the scheduled tasks stored at `path`, a missing file means no tasks.
+    pub async fn load(path: PathBuf) -> Result<Self, Error> {
+        let tasks = match tokio::fs::read(&path).await {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e.into()),
+        };
+
+        debug!("loaded scheduled tasks from {path:?}: {tasks:?}");
+
+        Ok(Self {
+            path,
+            tasks: Mutex::new(tasks),
+            changed: Notify::new(),
+            running: Default::default(),
+        })
+    }
+
+    /// Adds `task`, replacing the scheduled task with the same name.
+    pub async fn add(&self, task: ScheduledTask) -> Result<(), Error> {
+        match &task.schedule {
+            Schedule::Cron { expr } => {
+                cron::Schedule::from_str(expr)?;
+            }
+            Schedule::Interval { every } if *every < MIN_INTERVAL => {
+                return Err(Error::IntervalTooShort(*every, MIN_INTERVAL));
+            }
+            Schedule::Interval { .. } => {}
+        }
+
+        let mut tasks = self.tasks.lock().await;
+        tasks.retain(|t| t.name != task.name);
+        tasks.push(task);
+        self.save(&tasks).await?;
+        self.changed.notify_one();
+
+        Ok(())
+    }
+
+    pub async fn remove(&self, name: &str) -> Result<(), Error> {
+        let mut tasks = self.tasks.lock().await;
+        let len = tasks.len();
+        tasks.retain(|t| t.name != name);
+        if tasks.len() == len {
+            return Err(Error::NotFoundSchedTask(name.into()));
+        }
+        self.save(&tasks).await?;
+        self.changed.notify_one();
+
+        Ok(())
+    }
+
+    pub async fn list(&self) -> Vec<ScheduledTask> {
+        self.tasks.lock().await.clone()
+    }
+
+    async fn save(&self, tasks: &[ScheduledTask]) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        // Write to a sibling file first, so a crash never leaves a truncated file behind.
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec_pretty(tasks)?).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+
+        Ok(())
+    }
+
+    /// Runs the scheduled tasks when they are due and publishes their results to `subject`.
+    pub async fn run(
+        &self,
+        agent_id: &str,
+        subject: &str,
+        log_dir: &Path,
+        client: &async_nats::Client,
+        jobs: &Jobs,
+    ) {
+        // name -> (schedule the next run was computed from, next run)
+        let mut next_runs: HashMap<String, (Schedule, DateTime<Utc>)> = HashMap::new();
+
+        loop {
+            let now = Utc::now();
+            let tasks = self.list().await;
+
+            next_runs.retain(|name, (schedule, _)| {
+                tasks
+                    .iter()
+                    .any(|t| &t.name == name && &t.schedule == schedule)
+            });
+
+            for task in &tasks {
+                if !next_runs.contains_key(&task.name) {
+                    if let Some(next) = next_run(&task.schedule, now) {
+                        next_runs.insert(task.name.clone(), (task.schedule.clone(), next));
+                    }
+                }
+            }
+
+            for task in &tasks {
+                let Some((_, next)) = next_runs.get_mut(&task.name) else {
+                    continue;
+                };
+                if *next > now {
+                    continue;
+                }
+                match next_run(&task.schedule, now) {
+                    Some(n) => *next = n,
+                    None => {
+                        next_runs.remove(&task.name);
+                    }
+                }
+
+                let mut running = self.running.lock().await;
+                running.retain(|_, run| !run.is_finished());
+                if running.contains_key(&task.name) {
+                    debug!("scheduled task {} still runs, skip this run.", task.name);
+                    continue;
+                }
+
+                debug!("run scheduled task: {}", task.name);
+
+                let name = task.name.clone();
+                let task = task.clone();
+                let agent_id = agent_id.to_string();
+                let subject = subject.to_string();
+                let client = client.clone();
+                let jobs = jobs.clone();
+                let log_dir = log_dir.to_path_buf();
+                let run = tokio::spawn(async move {
+                    let ScheduledTask { name, task, .. } = task;
+                    let id = task.id;
+                    let results = crate::task::run_task(task, &jobs, &log_dir).await;
+                    let res = async {
+                        let payload = serde_json::to_vec(&SchedTaskResultNats {
+                            agent_id,
+                            name,
+                            id,
+                            results,
+                        })?;
+                        client.publish(subject, payload.into()).await?;
+                        client
+                            .flush()
+                            .await
+                            .map_err(|e| Error::AsyncNatsError(e.into()))?;
+                        Result::<(), Error>::Ok(())
+                    };
+                    if let Err(e) = res.await {
+                        error!("Publish scheduled task result failed: {e:?}");
+                    }
+                });
+                running.insert(name, run);
+            }
+
+            let sleep = next_runs
+                .values()
+                .map(|(_, next)| *next)
+                .min()
+                .map(|next| (next - Utc::now()).to_std().unwrap_or_default());
+
+            trace!("scheduler sleeps for {sleep:?}");
+
+            match sleep {
+                Some(sleep) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(sleep) => {},
+                        _ = self.changed.notified() => {},
+                    }
+                }
+                None => self.changed.notified().await,
+            }
+        }
+    }
+}
+
+impl Scheduler {
+    /// Waits for the runs in progress to finish.
+    pub async fn drain(&self) {
+        let mut running = self.running.lock().await;
+        for run in running.values_mut() {
+            if let Err(e) = run.await {
+                error!("scheduled task run failed: {e:?}");
+            }
+        }
+        running.clear();
+    }
+
+    /// Aborts the runs still in progress, their jobs should be cancelled first.
+    pub async fn abort_all(&self) {
+        for (_, run) in self.running.lock().await.drain() {
+            run.abort();
+        }
+    }
+}
+
+fn next_run(schedule: &Schedule, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    match schedule {
+        Schedule::Cron { expr } => match cron::Schedule::from_str(expr) {
+            Ok(schedule) => schedule.after(&now).next(),
+            Err(e) => {
+                error!("invalid cron expression {expr:?}: {e}");
+                None
+            }
+        },
+        // Tasks persisted before the minimum was enforced mustn't spin either.
+        Schedule::Interval { every } => chrono::Duration::from_std((*every).max(MIN_INTERVAL))
+            .ok()
+            .map(|every| now + every),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::AutomatedTask;
+
+    fn sched_task(name: &str, schedule: Schedule) -> ScheduledTask {
+        ScheduledTask {
+            name: name.into(),
+            schedule,
+            task: AutomatedTask::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_persist_sched_tasks() {
+        let path = std::env::temp_dir()
+            .join(format!("ironhive-test-sched-{}", std::process::id()))
+            .join("sched_tasks.json");
+
+        let scheduler = Scheduler::load(path.clone()).await.unwrap();
+        scheduler
+            .add(sched_task(
+                "every-minute",
+                Schedule::Cron {
+                    expr: "0 * * * * *".into(),
+                },
+            ))
+            .await
+            .unwrap();
+        scheduler
+            .add(sched_task(
+                "interval",
+                Schedule::Interval {
+                    every: std::time::Duration::from_secs(30),
+                },
+            ))
+            .await
+            .unwrap();
+        assert!(scheduler
+            .add(sched_task(
+                "invalid",
+                Schedule::Cron {
+                    expr: "not a cron".into(),
+                },
+            ))
+            .await
+            .is_err());
+        assert!(matches!(
+            scheduler
+                .add(sched_task(
+                    "busy-loop",
+                    Schedule::Interval {
+                        every: Duration::ZERO,
+                    },
+                ))
+                .await,
+            Err(Error::IntervalTooShort(..))
+        ));
+        scheduler.remove("interval").await.unwrap();
+        assert!(matches!(
+            scheduler.remove("interval").await,
+            Err(Error::NotFoundSchedTask(_))
+        ));
+
+        let reloaded = Scheduler::load(path.clone()).await.unwrap();
+        assert_eq!(reloaded.list().await, scheduler.list().await);
+        assert_eq!(reloaded.list().await.len(), 1);
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_drain_runs() {
+        let path = std::env::temp_dir()
+            .join(format!("ironhive-test-sched-drain-{}", std::process::id()))
+            .join("sched_tasks.json");
+        let scheduler = Scheduler::load(path).await.unwrap();
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        scheduler.running.lock().await.insert(
+            "quick".into(),
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let _ = tx.send(());
+            }),
+        );
+        scheduler.drain().await;
+        assert!(rx.await.is_ok());
+
+        let stuck = tokio::spawn(std::future::pending::<()>());
+        let abort = stuck.abort_handle();
+        scheduler.running.lock().await.insert("stuck".into(), stuck);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), scheduler.drain())
+                .await
+                .is_err()
+        );
+        scheduler.abort_all().await;
+        tokio::task::yield_now().await;
+        assert!(abort.is_finished());
+        assert!(scheduler.running.lock().await.is_empty());
+    }
+
+    #[test]
+    fn test_next_run() {
+        let now = Utc::now();
+        let next = next_run(
+            &Schedule::Interval {
+                every: std::time::Duration::from_secs(30),
+            },
+            now,
+        );
+        assert_eq!(next, Some(now + chrono::Duration::seconds(30)));
+
+        let next = next_run(
+            &Schedule::Cron {
+                expr: "0 * * * * *".into(),
+            },
+            now,
+        )
+        .unwrap();
+        assert!(next > now && next - now <= chrono::Duration::seconds(60));
+
+        let next = next_run(
+            &Schedule::Interval {
+                every: Duration::from_millis(1),
+            },
+            now,
+        );
+        assert_eq!(next, Some(now + chrono::Duration::seconds(1)));
+    }
+}
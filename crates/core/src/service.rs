@@ -0,0 +1,249 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use async_nats::service::{endpoint::Endpoint, Request, Service, ServiceExt};
+use serde::Serialize;
+use shared::{IronhiveError, IronhiveRequest};
+
+use crate::{agent::Agent, error::Error};
+
+/// Name every agent registers its service under, so `$SRV.PING.ironhive` finds all of them.
+pub const SERVICE_NAME: &str = "ironhive";
+
+/// The endpoint on the agent's subject, taking requests of any function.
+pub const REQUEST_ENDPOINT: &str = "request";
+
+/// Registers the agent as a NATS micro-service.
+///
+/// Next to the agent's subject, see [`Agent::subject`], every function the agent supports
+/// gets its own endpoint on `{subject}.{func}`, only taking requests of that function. NATS
+/// permissions on these subjects decide who may call what.
+pub(crate) async fn start(
+    client: &async_nats::Client,
+    agent: &Agent,
+) -> Result<(Service, Vec<Endpoint>, ErrorStats), Error> {
+    if let Some(prefix) = &agent.subject_prefix {
+        check_subject_prefix(prefix)?;
+    }
+    let subject = agent.subject();
+    let errors = ErrorStats::default();
+
+    let metadata = HashMap::from([
+        ("agent_id".to_string(), agent.agent_id.clone()),
+        ("os".to_string(), std::env::consts::OS.to_string()),
+        ("arch".to_string(), std::env::consts::ARCH.to_string()),
+        (
+            "protocol_version".to_string(),
+            shared::PROTOCOL_VERSION.to_string(),
+        ),
+    ]);
+
+    let service = client
+        .service_builder()
+        .description("Ironhive agent")
+        .metadata(metadata)
+        .stats_handler({
+            let errors = errors.clone();
+            move |endpoint, _| errors.data(&endpoint)
+        })
+        .start(SERVICE_NAME, agent.version())
+        .await?;
+
+    let mut endpoints = vec![
+        service
+            .endpoint_builder()
+            .name(REQUEST_ENDPOINT)
+            .add(subject.clone())
+            .await?,
+    ];
+    for func in crate::agent::supported_functions() {
+        let endpoint = service
+            .endpoint_builder()
+            .name(&func)
+            .add(format!("{subject}.{func}"))
+            .await?;
+        endpoints.push(endpoint);
+    }
+
+    Ok((service, endpoints, errors))
+}
+
+/// Subscribes to the subjects of the agent's groups, see [`shared::group_subject`].
+///
+/// Plain subscriptions, as all instances of a service endpoint share a queue group and only
+/// one agent would get each request.
+pub(crate) async fn subscribe_groups(
+    client: &async_nats::Client,
+    agent: &Agent,
+) -> Result<Vec<async_nats::Subscriber>, Error> {
+    let mut subscribers = Vec::with_capacity(agent.groups.len());
+    for group in &agent.groups {
+        if !valid_subject_tokens(group) {
+            return Err(Error::InvalidGroup(group.clone()));
+        }
+        let subject = shared::group_subject(agent.subject_prefix.as_deref(), group);
+        subscribers.push(client.subscribe(subject).await?);
+    }
+    Ok(subscribers)
+}
+
+fn check_subject_prefix(prefix: &str) -> Result<(), Error> {
+    if valid_subject_tokens(prefix) {
+        Ok(())
+    } else {
+        Err(Error::InvalidSubjectPrefix(prefix.to_string()))
+    }
+}
+
+/// Plain tokens separated by dots, without wildcards.
+fn valid_subject_tokens(subject: &str) -> bool {
+    subject.split('.').all(|token| {
+        !token.is_empty()
+            && !token
+                .chars()
+                .any(|c| c.is_whitespace() || c == '*' || c == '>')
+    })
+}
+
+/// A request and how it reached the agent.
+pub(crate) enum Inbound {
+    /// Through one of the service endpoints.
+    Endpoint(Request),
+    /// Through a group subject, replied to with the agent id attached.
+    Group(async_nats::Message),
+}
+
+impl Inbound {
+    pub fn message(&self) -> &async_nats::Message {
+        match self {
+            Inbound::Endpoint(request) => &request.message,
+            Inbound::Group(message) => message,
+        }
+    }
+}
+
+/// The endpoint a request on `subject` came in through, `agent_subject` being the agent's.
+pub(crate) fn endpoint_name<'s>(agent_subject: &str, subject: &'s str) -> &'s str {
+    subject
+        .strip_prefix(agent_subject)
+        .and_then(|func| func.strip_prefix('.'))
+        .unwrap_or(REQUEST_ENDPOINT)
+}
+
+/// Reads a request that came in through `endpoint`.
+///
+/// Requests to a function endpoint may leave out `func`, or be empty if the function takes no
+/// arguments, but can't be of another function.
+pub(crate) fn parse_request(endpoint: &str, payload: &[u8]) -> Result<IronhiveRequest, Error> {
+    if endpoint == REQUEST_ENDPOINT {
+        return Ok(serde_json::from_slice(payload)?);
+    }
+
+    let mut value = if payload.is_empty() {
+        serde_json::Value::Object(Default::default())
+    } else {
+        serde_json::from_slice(payload)?
+    };
+    if let serde_json::Value::Object(map) = &mut value {
+        map.entry("func").or_insert_with(|| endpoint.into());
+    }
+
+    let request = serde_json::from_value::<IronhiveRequest>(value)?;
+    if request.func() != endpoint {
+        return Err(Error::FuncMismatch {
+            expected: endpoint.to_string(),
+            got: request.func().to_string(),
+        });
+    }
+    Ok(request)
+}
+
+/// Error replies per endpoint.
+///
+/// The service API sends error replies without a body, ours carry an [`IronhiveError`] and are
+/// published directly. They are counted here instead and show up in the `data` of the endpoint
+/// stats, `num_requests` and the processing times only cover successful requests.
+#[derive(Clone, Default)]
+pub(crate) struct ErrorStats {
+    endpoints: Arc<Mutex<HashMap<String, EndpointErrors>>>,
+}
+
+#[derive(Default, Clone, Serialize)]
+struct EndpointErrors {
+    num_errors: u64,
+    last_error: Option<IronhiveError>,
+}
+
+impl ErrorStats {
+    pub fn record(&self, endpoint: &str, err: &IronhiveError) {
+        let mut endpoints = self.lock();
+        let errors = endpoints.entry(endpoint.to_string()).or_default();
+        errors.num_errors += 1;
+        errors.last_error = Some(err.clone());
+    }
+
+    fn data(&self, endpoint: &str) -> serde_json::Value {
+        let errors = self.lock().get(endpoint).cloned().unwrap_or_default();
+        serde_json::to_value(errors).unwrap_or_default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, EndpointErrors>> {
+        self.endpoints.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            parse_request(REQUEST_ENDPOINT, br#"{"func":"ping"}"#).unwrap(),
+            IronhiveRequest::Ping
+        );
+        assert!(parse_request(REQUEST_ENDPOINT, b"").is_err());
+
+        assert_eq!(parse_request("procs", b"").unwrap(), IronhiveRequest::Procs);
+        assert_eq!(
+            parse_request("canceljob", br#"{"id":3}"#).unwrap(),
+            IronhiveRequest::CancelJob { id: 3 }
+        );
+        assert_eq!(
+            parse_request("canceljob", br#"{"func":"canceljob","id":3}"#).unwrap(),
+            IronhiveRequest::CancelJob { id: 3 }
+        );
+        assert!(matches!(
+            parse_request("ping", br#"{"func":"rebootnow"}"#),
+            Err(Error::FuncMismatch { .. })
+        ));
+        assert!(parse_request("canceljob", b"").is_err());
+
+        assert!(check_subject_prefix("ironhive").is_ok());
+        assert!(check_subject_prefix("acme.ironhive").is_ok());
+        assert!(check_subject_prefix("acme..ironhive").is_err());
+        assert!(check_subject_prefix("acme.*").is_err());
+        assert!(check_subject_prefix("").is_err());
+        assert!(!valid_subject_tokens("site berlin"));
+    }
+
+    #[test]
+    fn test_error_stats() {
+        assert_eq!(endpoint_name("agent", "agent"), REQUEST_ENDPOINT);
+        assert_eq!(endpoint_name("agent", "agent.runscript"), "runscript");
+        assert_eq!(endpoint_name("acme.agent", "acme.agent.procs"), "procs");
+
+        let errors = ErrorStats::default();
+        assert_eq!(errors.data("ping")["num_errors"], 0);
+
+        let err = IronhiveError::from(&Error::NotFoundJob(3));
+        errors.record("canceljob", &err);
+        errors.record("canceljob", &err);
+        let data = errors.data("canceljob");
+        assert_eq!(data["num_errors"], 2);
+        assert_eq!(data["last_error"]["code"], "not_found");
+        assert_eq!(errors.data("ping")["num_errors"], 0);
+    }
+}
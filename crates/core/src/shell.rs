@@ -0,0 +1,395 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::StreamExt;
+use shared::{ShellCloseReason, ShellClosedNats};
+use tokio::{sync::oneshot, task::JoinHandle};
+use tracing::{debug, error};
+
+use crate::{error::Error, utils::random_id};
+
+/// How long a closed shell gets to exit after its hangup before it is killed.
+const HANGUP_GRACE: Duration = Duration::from_secs(1);
+
+/// The subjects a shell session is relayed over.
+#[derive(Debug, Clone)]
+pub struct ShellSubjects {
+    pub input: String,
+    pub output: String,
+    pub closed: String,
+}
+
+impl ShellSubjects {
+    fn new(agent_subject: &str, session_id: &str) -> Self {
+        let prefix = format!("{agent_subject}.shell.{session_id}");
+        Self {
+            input: format!("{prefix}.input"),
+            output: format!("{prefix}.output"),
+            closed: format!("{prefix}.closed"),
+        }
+    }
+}
+
+struct Session {
+    pty: Arc<pty::Pty>,
+    close: Option<oneshot::Sender<ShellCloseReason>>,
+    task: JoinHandle<()>,
+}
+
+/// The open interactive shell sessions.
+///
+/// Cloning is cheap, all clones share the same sessions.
+#[derive(Clone, Default)]
+pub struct Shells {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl Shells {
+    /// Starts `shell` on a new PTY and relays it over NATS until it exits or is closed.
+    ///
+    /// Returns the session id, the shell's pid and the subjects of the session.
+    pub async fn open(
+        &self,
+        client: &async_nats::Client,
+        agent_subject: &str,
+        shell: Option<String>,
+        cols: u16,
+        rows: u16,
+        idle_timeout: Duration,
+    ) -> Result<(String, u32, ShellSubjects), Error> {
+        let id = random_id();
+        let subjects = ShellSubjects::new(agent_subject, &id);
+
+        let shell = shell
+            .or_else(|| std::env::var("SHELL").ok())
+            .unwrap_or_else(|| "/bin/sh".into());
+        let (pty, mut child) = pty::Pty::spawn(&shell, cols, rows)?;
+        let pid = child.id().unwrap_or_default();
+        let pty = Arc::new(pty);
+
+        // Subscribe before replying, so no early input is lost.
+        let mut input = client.subscribe(subjects.input.clone()).await?;
+
+        let (close, mut close_rx) = oneshot::channel();
+
+        let mut sessions = self.lock();
+        let task = tokio::spawn({
+            let client = client.clone();
+            let subjects = subjects.clone();
+            let pty = pty.clone();
+            let shells = self.clone();
+            let id = id.clone();
+            async move {
+                let mut buf = vec![0; 16 * 1024];
+                let idle = tokio::time::sleep(idle_timeout);
+                tokio::pin!(idle);
+                // Output waits in the terminal until the client listens, which it tells with
+                // its first input, so the first prompt isn't lost.
+                let mut ready = false;
+
+                let reason = loop {
+                    tokio::select! {
+                        read = pty.read(&mut buf), if ready => match read {
+                            Ok(0) | Err(_) => break ShellCloseReason::Exited,
+                            Ok(n) => {
+                                let data = bytes::Bytes::copy_from_slice(&buf[..n]);
+                                if let Err(e) = client.publish(subjects.output.clone(), data).await {
+                                    error!("Publish shell output failed: {e:?}");
+                                }
+                            }
+                        },
+                        msg = input.next() => match msg {
+                            Some(msg) => {
+                                ready = true;
+                                if let Err(e) = pty.write_all(&msg.payload).await {
+                                    debug!("write shell {id} input failed: {e:?}");
+                                }
+                            }
+                            None => break ShellCloseReason::Shutdown,
+                        },
+                        reason = &mut close_rx => break reason.unwrap_or(ShellCloseReason::Shutdown),
+                        _ = child.wait(), if ready => {
+                            // Relay what it printed last, processes it left behind may keep
+                            // the terminal open.
+                            while let Ok(Ok(n @ 1..)) =
+                                tokio::time::timeout(Duration::from_millis(100), pty.read(&mut buf)).await
+                            {
+                                let data = bytes::Bytes::copy_from_slice(&buf[..n]);
+                                let _ = client.publish(subjects.output.clone(), data).await;
+                            }
+                            break ShellCloseReason::Exited;
+                        }
+                        _ = &mut idle => break ShellCloseReason::IdleTimeout,
+                    }
+                    idle.as_mut()
+                        .reset(tokio::time::Instant::now() + idle_timeout);
+                };
+                debug!("shell {id} closed: {reason:?}");
+
+                let _ = input.unsubscribe().await;
+                let status = pty::hangup(&mut child, pid).await;
+                shells.lock().remove(&id);
+
+                let closed = ShellClosedNats {
+                    session_id: id.clone(),
+                    reason,
+                    retcode: status.map(|status| {
+                        use std::os::unix::process::ExitStatusExt;
+                        status
+                            .code()
+                            .or_else(|| status.signal().map(|signal| 128 + signal))
+                            .unwrap_or(-1)
+                    }),
+                };
+                let res = async {
+                    client
+                        .publish(subjects.closed, serde_json::to_vec(&closed)?.into())
+                        .await?;
+                    client
+                        .flush()
+                        .await
+                        .map_err(|e| Error::AsyncNatsError(e.into()))
+                };
+                if let Err(e) = res.await {
+                    error!("Publish shell closed failed: {e:?}");
+                }
+            }
+        });
+        sessions.insert(
+            id.clone(),
+            Session {
+                pty,
+                close: Some(close),
+                task,
+            },
+        );
+
+        Ok((id, pid, subjects))
+    }
+
+    pub fn resize(&self, id: &str, cols: u16, rows: u16) -> Result<(), Error> {
+        let sessions = self.lock();
+        let session = sessions
+            .get(id)
+            .ok_or_else(|| Error::NotFoundShell(id.into()))?;
+        session.pty.resize(cols, rows)?;
+        Ok(())
+    }
+
+    /// Hangs up session `id`, it reports being closed on its closed subject.
+    pub fn close(&self, id: &str) -> Result<(), Error> {
+        let close = self
+            .lock()
+            .get_mut(id)
+            .and_then(|session| session.close.take())
+            .ok_or_else(|| Error::NotFoundShell(id.into()))?;
+        let _ = close.send(ShellCloseReason::Closed);
+        Ok(())
+    }
+
+    /// Hangs up all sessions and waits for them to finish.
+    pub async fn close_all(&self) {
+        let sessions = self.lock().drain().collect::<Vec<_>>();
+        for (_, mut session) in sessions {
+            if let Some(close) = session.close.take() {
+                let _ = close.send(ShellCloseReason::Shutdown);
+            }
+            let _ = session.task.await;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Session>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+mod pty {
+    use std::{
+        io,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+        process::{ExitStatus, Stdio},
+    };
+
+    use tokio::{
+        io::unix::AsyncFd,
+        process::{Child, Command},
+    };
+
+    use super::HANGUP_GRACE;
+    use crate::error::Error;
+
+    /// The master side of a pseudo terminal.
+    pub struct Pty {
+        master: AsyncFd<OwnedFd>,
+    }
+
+    impl Pty {
+        /// Starts `shell` as a session leader with the slave side as its controlling terminal.
+        pub fn spawn(shell: &str, cols: u16, rows: u16) -> Result<(Self, Child), Error> {
+            let (mut master, mut slave) = (0, 0);
+            let size = winsize(cols, rows);
+            if unsafe {
+                libc::openpty(
+                    &mut master,
+                    &mut slave,
+                    std::ptr::null_mut(),
+                    std::ptr::null(),
+                    &size,
+                )
+            } != 0
+            {
+                return Err(io::Error::last_os_error().into());
+            }
+            // Safety: both were just opened and are owned by nothing else.
+            let (master, slave) =
+                unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+
+            for fd in [&master, &slave] {
+                // Don't leak them into other processes the agent starts.
+                if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+                    return Err(io::Error::last_os_error().into());
+                }
+            }
+            let flags = unsafe { libc::fcntl(master.as_raw_fd(), libc::F_GETFL) };
+            if flags == -1
+                || unsafe {
+                    libc::fcntl(master.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK)
+                } == -1
+            {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            let mut cmd = Command::new(shell);
+            cmd.env("TERM", "xterm-256color")
+                .stdin(Stdio::from(slave.try_clone()?))
+                .stdout(Stdio::from(slave.try_clone()?))
+                .stderr(Stdio::from(slave))
+                .kill_on_drop(true);
+            if let Some(home) = std::env::var_os("HOME") {
+                cmd.current_dir(home);
+            }
+            unsafe {
+                // Safety: `setsid` and `ioctl` are async-signal-safe.
+                cmd.pre_exec(|| {
+                    if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+            let child = cmd.spawn()?;
+
+            Ok((
+                Self {
+                    master: AsyncFd::new(master)?,
+                },
+                child,
+            ))
+        }
+
+        /// Reads output of the shell, 0 once the terminal is gone.
+        pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                let mut guard = self.master.readable().await?;
+                match guard.try_io(|fd| {
+                    let n =
+                        unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                    if n < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(n as usize)
+                }) {
+                    // Linux reports EIO once all slave fds are closed.
+                    Ok(Err(e)) if e.raw_os_error() == Some(libc::EIO) => return Ok(0),
+                    Ok(res) => return res,
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+
+        pub async fn write_all(&self, mut data: &[u8]) -> io::Result<()> {
+            while !data.is_empty() {
+                let mut guard = self.master.writable().await?;
+                match guard.try_io(|fd| {
+                    let n =
+                        unsafe { libc::write(fd.as_raw_fd(), data.as_ptr().cast(), data.len()) };
+                    if n < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(n as usize)
+                }) {
+                    Ok(n) => data = &data[n?..],
+                    Err(_would_block) => continue,
+                }
+            }
+            Ok(())
+        }
+
+        pub fn resize(&self, cols: u16, rows: u16) -> io::Result<()> {
+            let size = winsize(cols, rows);
+            if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ as _, &size) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    fn winsize(cols: u16, rows: u16) -> libc::winsize {
+        libc::winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+
+    /// Hangs up the session of `child` like a closed terminal would, and kills whatever
+    /// is left of it after [`HANGUP_GRACE`].
+    pub async fn hangup(child: &mut Child, pid: u32) -> Option<ExitStatus> {
+        if let Ok(Some(status)) = child.try_wait() {
+            return Some(status);
+        }
+        // The shell leads its own session and process group.
+        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGHUP) };
+        if let Ok(Ok(status)) = tokio::time::timeout(HANGUP_GRACE, child.wait()).await {
+            return Some(status);
+        }
+        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+        child.wait().await.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pty() {
+        let (pty, mut child) = pty::Pty::spawn("/bin/sh", 80, 24).unwrap();
+        pty.resize(100, 40).unwrap();
+        pty.write_all(b"stty size; tty; exit 3\n").await.unwrap();
+
+        let mut output = vec![];
+        let mut buf = vec![0; 1024];
+        loop {
+            let n = tokio::time::timeout(Duration::from_secs(5), pty.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            if n == 0 {
+                break;
+            }
+            output.extend_from_slice(&buf[..n]);
+        }
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.contains("40 100"), "{output}");
+        assert!(!output.contains("not a tty"), "{output}");
+
+        let pid = child.id().unwrap();
+        let status = pty::hangup(&mut child, pid).await.unwrap();
+        assert_eq!(status.code(), Some(3));
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Asks a running [`crate::Ironhive`] to shut down gracefully.
+///
+/// Cloning is cheap, all clones stop the same agent.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self {
+            tx: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl ShutdownHandle {
+    /// Stops the agent taking requests, the ones in flight get to finish first.
+    pub fn shutdown(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Resolves once [`ShutdownHandle::shutdown`] was called.
+    pub(crate) async fn wait(&self) {
+        let mut rx = self.tx.subscribe();
+        // The sender lives in `self`, so this can't fail.
+        let _ = rx.wait_for(|shutdown| *shutdown).await;
+    }
+}
+
+/// Resolves on SIGINT or SIGTERM, or Ctrl-C on Windows.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = terminate.recv() => {},
+                }
+            }
+            Err(e) => {
+                tracing::error!("Listen for SIGTERM failed: {e:?}");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_handle() {
+        let handle = ShutdownHandle::default();
+        assert!(!handle.is_shutdown());
+
+        let waiting = handle.wait();
+        tokio::pin!(waiting);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), &mut waiting)
+                .await
+                .is_err()
+        );
+
+        handle.clone().shutdown();
+        waiting.await;
+        assert!(handle.is_shutdown());
+
+        // Waiting after the fact returns right away.
+        handle.wait().await;
+    }
+}
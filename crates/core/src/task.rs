@@ -0,0 +1,129 @@
+use std::path::Path;
+
+use shared::{AutomatedTask, TaskAction, TaskActionResult, Termination};
+use tracing::{debug, error, info};
+
+use crate::{
+    cmd::{CmdScript, CmdShell},
+    jobs::Jobs,
+};
+
+/// Runs every action of `task` in order and collects their results.
+///
+/// A failed action (an action that could not be started or exited with a
+/// non-zero code) stops the task unless `continue_on_error` is set.
+pub async fn run_task(task: AutomatedTask, jobs: &Jobs, log_dir: &Path) -> Vec<TaskActionResult> {
+    let AutomatedTask {
+        id,
+        task_actions,
+        enabled,
+        continue_on_error,
+    } = task;
+
+    let mut results = vec![];
+
+    if !enabled {
+        debug!("task {id} is disabled, skip it.");
+        return results;
+    }
+
+    for (n, action) in task_actions.into_iter().enumerate() {
+        debug!("will run action: {action:?}");
+
+        let job = Some(jobs.register(format!("task {id} action {n}")));
+
+        let action_start = std::time::Instant::now();
+
+        let res = match action {
+            TaskAction::CmdScript {
+                code,
+                mode,
+                args,
+                env_vars,
+                detached,
+                timeout,
+            } => {
+                CmdScript {
+                    code,
+                    mode,
+                    args,
+                    env_vars,
+                    detached: detached.then(|| log_dir.to_path_buf()),
+                    timeout,
+                    output: None,
+                    job,
+                    run_as_user: None,
+                    stdin: None,
+                    cwd: None,
+                    max_output_bytes: None,
+                }
+                .run()
+                .await
+            }
+            TaskAction::CmdShell {
+                shell,
+                command,
+                detached,
+                timeout,
+            } => {
+                CmdShell {
+                    shell,
+                    command,
+                    detached: detached.then(|| log_dir.to_path_buf()),
+                    timeout,
+                    output: None,
+                    job,
+                    run_as_user: None,
+                    stdin: None,
+                    cwd: None,
+                    max_output_bytes: None,
+                }
+                .run()
+                .await
+            }
+        };
+
+        let execution_time = std::time::Instant::now() - action_start;
+
+        info!("task {id} action execution time: {execution_time:?}");
+
+        let (result, failed) = match res {
+            Ok(output) => (
+                TaskActionResult {
+                    stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+                    stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+                    retcode: Some(output.retcode()),
+                    signal: output.signal(),
+                    signal_name: output.signal_name(),
+                    core_dumped: output.core_dumped(),
+                    termination: output.termination(),
+                    execution_time,
+                    timed_out: output.timed_out,
+                    error: None,
+                },
+                !output.status.success(),
+            ),
+            Err(e) => {
+                error!("task {id} action failed: {e:?}");
+                (
+                    TaskActionResult {
+                        termination: Termination::NotStarted,
+                        execution_time,
+                        error: Some((&e).into()),
+                        ..Default::default()
+                    },
+                    true,
+                )
+            }
+        };
+
+        results.push(result);
+
+        if failed && !continue_on_error {
+            debug!("task {id} stopped at a failed action.");
+            break;
+        }
+    }
+
+    results
+}
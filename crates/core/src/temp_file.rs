@@ -22,6 +22,11 @@ impl TempFile {
         &self.path
     }
 
+    /// Keeps the file on disk, e.g. for a detached process still reading it.
+    pub fn keep(mut self) -> PathBuf {
+        std::mem::take(&mut self.path)
+    }
+
     pub async fn new(prefix: &str, content: impl AsRef<[u8]>) -> Result<Self, Error> {
         let base = std::env::temp_dir();
         tokio::fs::create_dir_all(&base).await?;
@@ -36,6 +41,9 @@ impl TempFile {
             match tokio::fs::File::create(&path).await {
                 Ok(mut f) => {
                     f.write_all(content.as_ref()).await?;
+                    // Tokio writes in the background, make sure the script is on disk before
+                    // anything runs it.
+                    f.flush().await?;
                     return Ok(Self { path });
                 }
                 Err(err) if n_try == 1024 => {
@@ -50,10 +58,91 @@ impl TempFile {
 
 impl Drop for TempFile {
     fn drop(&mut self) {
+        // Taken by `keep`.
+        if self.path.as_os_str().is_empty() {
+            return;
+        }
         let _ = remove_file(&self.path);
     }
 }
 
+/// Creates `path` for writing, failing if anything is there already, a symlink included.
+pub(crate) async fn create_new(path: &Path) -> io::Result<tokio::fs::File> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NOFOLLOW);
+    options.open(path).await
+}
+
+/// Creates the directory `path` and its parents, only the agent may access it on Unix.
+///
+/// Fails if `path` is there already and isn't a directory of the agent's own, e.g. a symlink.
+pub(crate) async fn create_private_dir(path: &Path) -> io::Result<()> {
+    let mut builder = tokio::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(0o700);
+    builder.create(path).await?;
+
+    let meta = tokio::fs::symlink_metadata(path).await?;
+    let own = meta.is_dir();
+    #[cfg(unix)]
+    let own = {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        // Safety: `geteuid` can't fail.
+        let own = own && meta.uid() == unsafe { libc::geteuid() };
+        if own && meta.mode() & 0o077 != 0 {
+            // Created by an older agent.
+            tokio::fs::set_permissions(path, fs::Permissions::from_mode(0o700)).await?;
+        }
+        own
+    };
+    if own {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{path:?} is not a directory of the agent's own"),
+        ))
+    }
+}
+
+/// Opens `path` for writing, if it is a regular file of the agent's own and no link to
+/// another one.
+pub(crate) async fn open_own(path: &Path) -> io::Result<tokio::fs::File> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true);
+    // Non-blocking, so a FIFO planted at `path` can't hang the open.
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK);
+    let file = options.open(path).await?;
+    check_own(path, &file.metadata().await?)?;
+    Ok(file)
+}
+
+/// Fails unless `meta`, as returned by `symlink_metadata` or of an open file, is a regular
+/// file of the agent's own.
+pub(crate) fn check_own(path: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    let own = meta.is_file();
+    #[cfg(unix)]
+    let own = {
+        use std::os::unix::fs::MetadataExt;
+
+        // Safety: `geteuid` can't fail.
+        own && meta.uid() == unsafe { libc::geteuid() } && meta.nlink() == 1
+    };
+    if own {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{path:?} is not a regular file of the agent's own"),
+        ))
+    }
+}
+
 #[cfg(not(windows))]
 fn remove_file(path: &Path) -> io::Result<()> {
     fs::remove_file(path)
@@ -0,0 +1,547 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+use shared::{Encoding, FileChunkNats};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
+};
+use tracing::debug;
+
+use crate::{
+    error::Error,
+    temp_file::{check_own, create_new, open_own},
+};
+
+/// A file being uploaded in chunks, written to `part` next to `path` until it is complete.
+#[derive(Debug)]
+struct Upload {
+    path: PathBuf,
+    part: PathBuf,
+    size: u64,
+    sha256: String,
+    mode: Option<u32>,
+    owner: Option<String>,
+    received: u64,
+    last_active: Instant,
+}
+
+impl Upload {
+    fn same_file(&self, path: &Path, size: u64, sha256: &str) -> bool {
+        self.path == path && self.size == size && self.sha256.eq_ignore_ascii_case(sha256)
+    }
+}
+
+/// Uploads no chunk arrived for this long are dropped together with their part file.
+pub const UPLOAD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Chunked uploads in progress, by upload id.
+///
+/// Chunks have to arrive in order, a client that lost one starts the upload again with
+/// the same id and continues from the offset it is told. Each upload has its own lock, so
+/// a slow one doesn't hold back the others.
+#[derive(Default)]
+pub struct Uploads {
+    uploads: Mutex<HashMap<String, Arc<Mutex<Upload>>>>,
+}
+
+impl Uploads {
+    /// Starts or resumes upload `upload_id`, returns how many bytes the agent already has.
+    pub async fn start(
+        &self,
+        upload_id: String,
+        path: PathBuf,
+        size: u64,
+        sha256: String,
+        mode: Option<u32>,
+        owner: Option<String>,
+    ) -> Result<u64, Error> {
+        check_upload_id(&upload_id)?;
+        if !path.is_absolute() {
+            return Err(Error::NotAbsolutePath(path));
+        }
+        #[cfg(windows)]
+        if mode.is_some() || owner.is_some() {
+            return Err(Error::UnsupportedRequest(
+                "upload with mode or owner".into(),
+            ));
+        }
+        #[cfg(unix)]
+        if let Some(owner) = &owner {
+            // Fail before any data is sent.
+            crate::user::User::lookup(owner)?;
+        }
+
+        self.remove_expired(Instant::now()).await;
+
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(Error::NotAbsolutePath(path));
+        };
+        tokio::fs::create_dir_all(dir).await?;
+        // Next to the destination, so the final rename stays on the same file system.
+        let part = dir.join(format!(".{}.{upload_id}.part", name.to_string_lossy()));
+
+        let (upload, created) = {
+            let mut uploads = self.uploads.lock().await;
+            match uploads.get(&upload_id) {
+                Some(upload) => (upload.clone(), false),
+                None => {
+                    let upload = Arc::new(Mutex::new(Upload {
+                        path: path.clone(),
+                        part: part.clone(),
+                        size,
+                        sha256: sha256.clone(),
+                        mode,
+                        owner: owner.clone(),
+                        received: 0,
+                        last_active: Instant::now(),
+                    }));
+                    uploads.insert(upload_id.clone(), upload.clone());
+                    (upload, true)
+                }
+            }
+        };
+        let mut upload = upload.lock().await;
+
+        let received = if created {
+            // A part file left by an earlier run of the agent, the hash check covers its content.
+            // Anything else there, like a symlink, is replaced.
+            match tokio::fs::symlink_metadata(&part).await {
+                Ok(meta) if check_own(&part, &meta).is_ok() && meta.len() <= size => meta.len(),
+                _ => 0,
+            }
+        } else if upload.same_file(&path, size, &sha256) {
+            upload.received
+        } else {
+            debug!("upload {upload_id} changed, start it over");
+            let _ = tokio::fs::remove_file(&upload.part).await;
+            0
+        };
+
+        if received == 0 {
+            match tokio::fs::remove_file(&part).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            create_new(&part).await?;
+        }
+
+        *upload = Upload {
+            path,
+            part,
+            size,
+            sha256,
+            mode,
+            owner,
+            received,
+            last_active: Instant::now(),
+        };
+
+        Ok(received)
+    }
+
+    /// Writes `data` at `offset` of upload `upload_id`, returns how many bytes the agent has now.
+    pub async fn chunk(&self, upload_id: &str, offset: u64, data: &[u8]) -> Result<u64, Error> {
+        let upload = self.get(upload_id).await?;
+        let mut upload = upload.lock().await;
+
+        if offset != upload.received {
+            return Err(Error::UploadOffsetMismatch {
+                expected: upload.received,
+                got: offset,
+            });
+        }
+        if offset + data.len() as u64 > upload.size {
+            return Err(Error::UploadVerifyFailed(format!(
+                "{} bytes is more than the size of {}",
+                offset + data.len() as u64,
+                upload.size
+            )));
+        }
+
+        let mut file = open_own(&upload.part).await?;
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        file.write_all(data).await?;
+        file.flush().await?;
+
+        upload.received += data.len() as u64;
+        upload.last_active = Instant::now();
+
+        Ok(upload.received)
+    }
+
+    /// Verifies upload `upload_id` and moves it to its destination, returns its path and size.
+    pub async fn finish(&self, upload_id: &str) -> Result<(PathBuf, u64), Error> {
+        let entry = self.get(upload_id).await?;
+        let upload = entry.lock().await;
+
+        if upload.received != upload.size {
+            return Err(Error::UploadVerifyFailed(format!(
+                "received {} of {} bytes",
+                upload.received, upload.size
+            )));
+        }
+
+        {
+            let mut uploads = self.uploads.lock().await;
+            // Started over or finished by someone else while this waited for the lock.
+            if !uploads
+                .get(upload_id)
+                .is_some_and(|e| Arc::ptr_eq(e, &entry))
+            {
+                return Err(Error::NotFoundUpload(upload_id.to_string()));
+            }
+            uploads.remove(upload_id);
+        }
+
+        let sha256 = sha256_file(&upload.part).await?;
+        if !sha256.eq_ignore_ascii_case(&upload.sha256) {
+            let _ = tokio::fs::remove_file(&upload.part).await;
+            return Err(Error::UploadVerifyFailed(format!(
+                "sha256 is {sha256}, expected {}",
+                upload.sha256
+            )));
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            // Through the open file, so a swapped in symlink isn't followed.
+            let file = open_own(&upload.part).await?;
+            if let Some(mode) = upload.mode {
+                file.set_permissions(std::fs::Permissions::from_mode(mode))
+                    .await?;
+            }
+            if let Some(owner) = &upload.owner {
+                let user = crate::user::User::lookup(owner)?;
+                std::os::unix::fs::fchown(&file, Some(user.uid), Some(user.gid))?;
+            }
+        }
+
+        tokio::fs::rename(&upload.part, &upload.path).await?;
+
+        Ok((upload.path.clone(), upload.size))
+    }
+
+    async fn get(&self, upload_id: &str) -> Result<Arc<Mutex<Upload>>, Error> {
+        self.uploads
+            .lock()
+            .await
+            .get(upload_id)
+            .cloned()
+            .ok_or_else(|| Error::NotFoundUpload(upload_id.to_string()))
+    }
+
+    /// Drops the uploads idle for longer than [`UPLOAD_TTL`] at `now` and removes their part
+    /// files.
+    async fn remove_expired(&self, now: Instant) {
+        let mut parts = vec![];
+        self.uploads.lock().await.retain(|upload_id, upload| {
+            // Locked means a request is working on it right now.
+            let Ok(upload) = upload.try_lock() else {
+                return true;
+            };
+            let expired = now.saturating_duration_since(upload.last_active) > UPLOAD_TTL;
+            if expired {
+                debug!("upload {upload_id} expired");
+                parts.push(upload.part.clone());
+            }
+            !expired
+        });
+        for part in parts {
+            let _ = tokio::fs::remove_file(part).await;
+        }
+    }
+}
+
+/// Size of the chunks a download is split into, small enough to stay below the NATS payload
+/// limit once base64 encoded.
+pub const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// What [`download`] sent.
+#[derive(Debug)]
+pub struct Download {
+    /// Size of the whole file when the download started.
+    pub size: u64,
+    pub len: u64,
+    pub sha256: String,
+    pub chunks: u64,
+}
+
+/// Reads up to `max_len` bytes of the file at `path` from `offset` on and passes them to
+/// `publish` in chunks, followed by an empty last chunk carrying their sha256.
+pub async fn download<F, Fut>(
+    path: &Path,
+    offset: u64,
+    max_len: Option<u64>,
+    mut publish: F,
+) -> Result<Download, Error>
+where
+    F: FnMut(FileChunkNats) -> Fut,
+    Fut: Future<Output = Result<(), Error>>,
+{
+    if !path.is_absolute() {
+        return Err(Error::NotAbsolutePath(path.to_path_buf()));
+    }
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let size = file.metadata().await?.len();
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+
+    let mut hasher = Sha256::new();
+    let mut remaining = max_len.unwrap_or(u64::MAX);
+    let mut len = 0;
+    let mut seq = 0;
+    let mut buf = vec![0; DOWNLOAD_CHUNK_SIZE];
+
+    while remaining > 0 {
+        let want = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        // Fill whole chunks, a log file may still be growing.
+        let mut n = 0;
+        while n < want {
+            match file.read(&mut buf[n..want]).await? {
+                0 => break,
+                read => n += read,
+            }
+        }
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..n]);
+        publish(FileChunkNats {
+            seq,
+            offset: offset + len,
+            data: crate::utils::encode(&buf[..n], Encoding::Base64),
+            last: false,
+            sha256: None,
+        })
+        .await?;
+
+        seq += 1;
+        len += n as u64;
+        remaining -= n as u64;
+        if n < want {
+            break;
+        }
+    }
+
+    let sha256 = format!("{:x}", hasher.finalize());
+    publish(FileChunkNats {
+        seq,
+        offset: offset + len,
+        data: String::new(),
+        last: true,
+        sha256: Some(sha256.clone()),
+    })
+    .await?;
+
+    Ok(Download {
+        size,
+        len,
+        sha256,
+        chunks: seq + 1,
+    })
+}
+
+fn check_upload_id(upload_id: &str) -> Result<(), Error> {
+    // It becomes part of a file name.
+    if upload_id.is_empty()
+        || upload_id.len() > 128
+        || !upload_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(Error::InvalidUploadId(upload_id.to_string()));
+    }
+    Ok(())
+}
+
+/// The lowercase hex sha256 of the file at `path`.
+pub async fn sha256_file(path: &Path) -> Result<String, Error> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_upload() {
+        let dir = std::env::temp_dir().join("ironhive-test-upload");
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        let path = dir.join("file.bin");
+
+        let data = b"hello chunked upload".to_vec();
+        let sha256 = format!("{:x}", Sha256::digest(&data));
+
+        let uploads = Uploads::default();
+        let start = || {
+            uploads.start(
+                "up-1".into(),
+                path.clone(),
+                data.len() as u64,
+                sha256.clone(),
+                None,
+                None,
+            )
+        };
+
+        assert_eq!(start().await.unwrap(), 0);
+        assert_eq!(uploads.chunk("up-1", 0, &data[..5]).await.unwrap(), 5);
+
+        // A lost chunk.
+        assert!(matches!(
+            uploads.chunk("up-1", 10, &data[10..]).await,
+            Err(Error::UploadOffsetMismatch {
+                expected: 5,
+                got: 10
+            })
+        ));
+        assert!(matches!(
+            uploads.finish("up-1").await,
+            Err(Error::UploadVerifyFailed(_))
+        ));
+
+        // Resume where the agent is at.
+        assert_eq!(start().await.unwrap(), 5);
+        assert_eq!(uploads.chunk("up-1", 5, &data[5..]).await.unwrap(), 20);
+
+        let (final_path, size) = uploads.finish("up-1").await.unwrap();
+        assert_eq!(final_path, path);
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), data);
+
+        // A corrupted upload is not moved into place.
+        let bad = dir.join("bad.bin");
+        uploads
+            .start("up-2".into(), bad.clone(), 3, sha256.clone(), None, None)
+            .await
+            .unwrap();
+        uploads.chunk("up-2", 0, b"abc").await.unwrap();
+        assert!(matches!(
+            uploads.finish("up-2").await,
+            Err(Error::UploadVerifyFailed(_))
+        ));
+        assert!(!bad.exists());
+
+        // A symlink planted at the part path is replaced, not written through.
+        #[cfg(unix)]
+        {
+            let victim = dir.join("victim");
+            tokio::fs::write(&victim, b"keep me").await.unwrap();
+            let planted = dir.join("planted.bin");
+            std::os::unix::fs::symlink(&victim, dir.join(".planted.bin.up-3.part")).unwrap();
+            assert_eq!(
+                uploads
+                    .start("up-3".into(), planted, 3, sha256.clone(), None, None)
+                    .await
+                    .unwrap(),
+                0
+            );
+            uploads.chunk("up-3", 0, b"abc").await.unwrap();
+            assert_eq!(tokio::fs::read(&victim).await.unwrap(), b"keep me");
+        }
+
+        // An abandoned upload expires with its part file.
+        uploads
+            .start(
+                "up-4".into(),
+                dir.join("gone.bin"),
+                3,
+                sha256.clone(),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        uploads.chunk("up-4", 0, b"a").await.unwrap();
+        let part = dir.join(".gone.bin.up-4.part");
+        assert!(part.exists());
+        uploads
+            .remove_expired(Instant::now() + UPLOAD_TTL + Duration::from_secs(1))
+            .await;
+        assert!(!part.exists());
+        assert!(matches!(
+            uploads.chunk("up-4", 1, b"b").await,
+            Err(Error::NotFoundUpload(_))
+        ));
+
+        assert!(matches!(
+            uploads
+                .start("../up".into(), path.clone(), 0, sha256.clone(), None, None)
+                .await,
+            Err(Error::InvalidUploadId(_))
+        ));
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let path = std::env::temp_dir().join("ironhive-test-download.bin");
+        let data = (0..DOWNLOAD_CHUNK_SIZE * 2 + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        tokio::fs::write(&path, &data).await.unwrap();
+
+        let download_range = |offset, max_len| {
+            let path = path.clone();
+            async move {
+                let mut chunks = vec![];
+                let download = download(&path, offset, max_len, |chunk| {
+                    chunks.push(chunk);
+                    async { Ok(()) }
+                })
+                .await
+                .unwrap();
+                (download, chunks)
+            }
+        };
+
+        let (download, chunks) = download_range(0, None).await;
+        assert_eq!(download.size, data.len() as u64);
+        assert_eq!(download.len, data.len() as u64);
+        assert_eq!(download.sha256, format!("{:x}", Sha256::digest(&data)));
+        assert_eq!(download.chunks, 4);
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().enumerate().all(|(i, c)| c.seq == i as u64));
+        assert_eq!(chunks[1].offset, DOWNLOAD_CHUNK_SIZE as u64);
+        assert!(chunks[3].last);
+        assert_eq!(chunks[3].sha256.as_ref(), Some(&download.sha256));
+
+        let received = chunks
+            .iter()
+            .flat_map(|c| crate::utils::decode(&c.data, Encoding::Base64).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(received, data);
+
+        let (download, chunks) = download_range(10, Some(20)).await;
+        assert_eq!(download.len, 20);
+        assert_eq!(
+            download.sha256,
+            format!("{:x}", Sha256::digest(&data[10..30]))
+        );
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].offset, 10);
+
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+}
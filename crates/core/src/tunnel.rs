@@ -0,0 +1,196 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::StreamExt;
+use shared::{TunnelCloseReason, TunnelClosedNats};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::oneshot,
+    task::JoinHandle,
+};
+use tracing::{debug, error};
+
+use crate::{error::Error, utils::random_id};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The subjects a tunnel is bridged over.
+#[derive(Debug, Clone)]
+pub struct TunnelSubjects {
+    pub input: String,
+    pub output: String,
+    pub closed: String,
+}
+
+impl TunnelSubjects {
+    fn new(agent_subject: &str, tunnel_id: &str) -> Self {
+        let prefix = format!("{agent_subject}.tunnel.{tunnel_id}");
+        Self {
+            input: format!("{prefix}.input"),
+            output: format!("{prefix}.output"),
+            closed: format!("{prefix}.closed"),
+        }
+    }
+}
+
+struct Tunnel {
+    close: Option<oneshot::Sender<TunnelCloseReason>>,
+    task: JoinHandle<()>,
+}
+
+/// The open TCP tunnels, each bridging one connection made by the agent.
+///
+/// Cloning is cheap, all clones share the same tunnels.
+#[derive(Clone, Default)]
+pub struct Tunnels {
+    tunnels: Arc<Mutex<HashMap<String, Tunnel>>>,
+}
+
+impl Tunnels {
+    /// Connects to `host:port` and bridges the connection over NATS until either side
+    /// closes it.
+    ///
+    /// The target is only read from after the first message on the input subject, so a client
+    /// that subscribed to the output subject by then doesn't miss a greeting of the target.
+    ///
+    /// Returns the tunnel id and its subjects.
+    pub async fn open(
+        &self,
+        client: &async_nats::Client,
+        agent_subject: &str,
+        host: &str,
+        port: u16,
+        idle_timeout: Duration,
+    ) -> Result<(String, TunnelSubjects), Error> {
+        let stream =
+            tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port))).await??;
+        let _ = stream.set_nodelay(true);
+
+        let id = random_id();
+        let subjects = TunnelSubjects::new(agent_subject, &id);
+
+        // Subscribe before replying, so no early input is lost.
+        let mut input = client.subscribe(subjects.input.clone()).await?;
+
+        let (close, mut close_rx) = oneshot::channel();
+
+        let mut tunnels = self.lock();
+        let task = tokio::spawn({
+            let client = client.clone();
+            let subjects = subjects.clone();
+            let tunnels = self.clone();
+            let id = id.clone();
+            async move {
+                let (mut reader, mut writer) = stream.into_split();
+                let mut buf = vec![0; 32 * 1024];
+                let idle = tokio::time::sleep(idle_timeout);
+                tokio::pin!(idle);
+                let mut ready = false;
+
+                let reason = loop {
+                    tokio::select! {
+                        read = reader.read(&mut buf), if ready => match read {
+                            Ok(0) => break TunnelCloseReason::TargetClosed,
+                            Ok(n) => {
+                                let data = bytes::Bytes::copy_from_slice(&buf[..n]);
+                                if let Err(e) = client.publish(subjects.output.clone(), data).await {
+                                    error!("Publish tunnel output failed: {e:?}");
+                                }
+                            }
+                            Err(e) => {
+                                debug!("read tunnel {id} target failed: {e:?}");
+                                break TunnelCloseReason::Error;
+                            }
+                        },
+                        msg = input.next() => match msg {
+                            // The client listens, an empty first message doesn't end its input.
+                            Some(msg) if !ready => {
+                                ready = true;
+                                if let Err(e) = writer.write_all(&msg.payload).await {
+                                    debug!("write tunnel {id} target failed: {e:?}");
+                                    break TunnelCloseReason::Error;
+                                }
+                            }
+                            // The other end has nothing more to send.
+                            Some(msg) if msg.payload.is_empty() => {
+                                let _ = writer.shutdown().await;
+                            }
+                            Some(msg) => {
+                                if let Err(e) = writer.write_all(&msg.payload).await {
+                                    debug!("write tunnel {id} target failed: {e:?}");
+                                    break TunnelCloseReason::Error;
+                                }
+                            }
+                            None => break TunnelCloseReason::Shutdown,
+                        },
+                        reason = &mut close_rx => break reason.unwrap_or(TunnelCloseReason::Shutdown),
+                        _ = &mut idle => break TunnelCloseReason::IdleTimeout,
+                    }
+                    idle.as_mut()
+                        .reset(tokio::time::Instant::now() + idle_timeout);
+                };
+                debug!("tunnel {id} closed: {reason:?}");
+
+                let _ = input.unsubscribe().await;
+                tunnels.lock().remove(&id);
+
+                let closed = TunnelClosedNats {
+                    tunnel_id: id.clone(),
+                    reason,
+                };
+                let res = async {
+                    client.publish(subjects.output, bytes::Bytes::new()).await?;
+                    client
+                        .publish(subjects.closed, serde_json::to_vec(&closed)?.into())
+                        .await?;
+                    client
+                        .flush()
+                        .await
+                        .map_err(|e| Error::AsyncNatsError(e.into()))
+                };
+                if let Err(e) = res.await {
+                    error!("Publish tunnel closed failed: {e:?}");
+                }
+            }
+        });
+        tunnels.insert(
+            id.clone(),
+            Tunnel {
+                close: Some(close),
+                task,
+            },
+        );
+
+        Ok((id, subjects))
+    }
+
+    /// Closes tunnel `id`, it reports being closed on its closed subject.
+    pub fn close(&self, id: &str) -> Result<(), Error> {
+        let close = self
+            .lock()
+            .get_mut(id)
+            .and_then(|tunnel| tunnel.close.take())
+            .ok_or_else(|| Error::NotFoundTunnel(id.into()))?;
+        let _ = close.send(TunnelCloseReason::Closed);
+        Ok(())
+    }
+
+    /// Closes all tunnels and waits for them to finish.
+    pub async fn close_all(&self) {
+        let tunnels = self.lock().drain().collect::<Vec<_>>();
+        for (_, mut tunnel) in tunnels {
+            if let Some(close) = tunnel.close.take() {
+                let _ = close.send(TunnelCloseReason::Shutdown);
+            }
+            let _ = tunnel.task.await;
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Tunnel>> {
+        self.tunnels.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    ffi::{CStr, CString, OsStr},
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+};
+
+use crate::error::Error;
+
+/// An account of the local user database, as needed to run a process as it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    /// Supplementary groups, including `gid`.
+    pub groups: Vec<libc::gid_t>,
+    pub home: PathBuf,
+    pub shell: PathBuf,
+}
+
+impl User {
+    /// Looks up `name` in the user database.
+    pub fn lookup(name: &str) -> Result<Self, Error> {
+        let not_found = || Error::NotFoundUser(name.to_string());
+        let c_name = CString::new(name).map_err(|_| not_found())?;
+
+        let mut buf_len = match unsafe { libc::sysconf(libc::_SC_GETPW_R_SIZE_MAX) } {
+            n if n > 0 => n as usize,
+            _ => 1024,
+        };
+
+        loop {
+            let mut buf = vec![0 as libc::c_char; buf_len];
+            let mut passwd = unsafe { std::mem::zeroed::<libc::passwd>() };
+            let mut result = std::ptr::null_mut();
+
+            let ret = unsafe {
+                libc::getpwnam_r(
+                    c_name.as_ptr(),
+                    &mut passwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            };
+
+            if ret == libc::ERANGE {
+                buf_len *= 2;
+                continue;
+            }
+            if ret != 0 {
+                return Err(std::io::Error::from_raw_os_error(ret).into());
+            }
+            if result.is_null() {
+                return Err(not_found());
+            }
+
+            // Safety: on success the strings point into `buf`, which is still alive.
+            let (home, shell) =
+                unsafe { (path_from_ptr(passwd.pw_dir), path_from_ptr(passwd.pw_shell)) };
+
+            return Ok(Self {
+                name: name.to_string(),
+                uid: passwd.pw_uid,
+                gid: passwd.pw_gid,
+                groups: group_list(&c_name, passwd.pw_gid),
+                home,
+                shell,
+            });
+        }
+    }
+}
+
+/// The name of the user with id `uid`, if there is one.
+pub fn user_name(uid: libc::uid_t) -> Option<String> {
+    let mut buf_len = match unsafe { libc::sysconf(libc::_SC_GETPW_R_SIZE_MAX) } {
+        n if n > 0 => n as usize,
+        _ => 1024,
+    };
+
+    loop {
+        let mut buf = vec![0 as libc::c_char; buf_len];
+        let mut passwd = unsafe { std::mem::zeroed::<libc::passwd>() };
+        let mut result = std::ptr::null_mut();
+
+        let ret =
+            unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+
+        if ret == libc::ERANGE {
+            buf_len *= 2;
+            continue;
+        }
+        if ret != 0 || result.is_null() || passwd.pw_name.is_null() {
+            return None;
+        }
+
+        // Safety: on success the name points into `buf`, which is still alive.
+        let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+        return Some(name.to_string_lossy().into_owned());
+    }
+}
+
+unsafe fn path_from_ptr(ptr: *const libc::c_char) -> PathBuf {
+    if ptr.is_null() {
+        return PathBuf::new();
+    }
+    PathBuf::from(OsStr::from_bytes(CStr::from_ptr(ptr).to_bytes()))
+}
+
+/// The groups `name` is a member of, falls back to just `gid` if they can't be listed.
+fn group_list(name: &CStr, gid: libc::gid_t) -> Vec<libc::gid_t> {
+    let mut len: libc::c_int = 32;
+    loop {
+        let mut groups = vec![0 as libc::gid_t; len as usize];
+        let ret = unsafe {
+            libc::getgrouplist(
+                name.as_ptr(),
+                gid as _,
+                groups.as_mut_ptr().cast(),
+                &mut len,
+            )
+        };
+        if ret >= 0 {
+            groups.truncate(len as usize);
+            return groups;
+        }
+        // Some platforms don't report the needed size, so grow it ourselves.
+        if len as usize <= groups.len() {
+            if groups.len() >= 65536 {
+                return vec![gid];
+            }
+            len = groups.len() as libc::c_int * 2;
+        }
+    }
+}
+
+/// Drops the privileges of the current process to the given ids.
+///
+/// Meant to run between fork and exec, so it only uses async-signal-safe calls.
+pub(crate) fn switch_to(
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    groups: &[libc::gid_t],
+) -> std::io::Result<()> {
+    unsafe {
+        if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0
+            || libc::setgid(gid) != 0
+            || libc::setuid(uid) != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_user() {
+        let root = User::lookup("root").unwrap();
+        assert_eq!(root.uid, 0);
+        assert_eq!(root.gid, 0);
+        assert!(root.groups.contains(&0));
+        assert_eq!(user_name(0).as_deref(), Some("root"));
+
+        assert!(matches!(
+            User::lookup("ironhive-no-such-user"),
+            Err(Error::NotFoundUser(_))
+        ));
+    }
+}
@@ -1,5 +1,8 @@
 use std::net::IpAddr;
 
+use base64::Engine;
+use shared::{Encoding, StdinData};
+
 use crate::error::Error;
 
 pub async fn public_ip() -> Result<IpAddr, Error> {
@@ -8,7 +11,41 @@ pub async fn public_ip() -> Result<IpAddr, Error> {
     Ok(ip)
 }
 
+pub fn decode(data: &str, encoding: Encoding) -> Result<Vec<u8>, Error> {
+    match encoding {
+        Encoding::Utf8 => Ok(data.as_bytes().to_vec()),
+        Encoding::Base64 => Ok(base64::engine::general_purpose::STANDARD.decode(data)?),
+    }
+}
+
+/// Encodes output for a json reply, invalid utf-8 is replaced when `encoding` is utf8.
+pub fn encode(data: &[u8], encoding: Encoding) -> String {
+    match encoding {
+        Encoding::Utf8 => String::from_utf8_lossy(data).to_string(),
+        Encoding::Base64 => base64::engine::general_purpose::STANDARD.encode(data),
+    }
+}
+
+/// A random id for a shell session or a tunnel, it is part of their subjects and mustn't be
+/// guessable.
+pub fn random_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+pub fn decode_stdin(stdin: StdinData) -> Result<Vec<u8>, Error> {
+    decode(&stdin.data, stdin.encoding)
+}
+
 #[tokio::test]
 async fn test_public_ip() {
     println!("{:?}", public_ip().await);
 }
+
+#[test]
+fn test_decode() {
+    assert_eq!(decode("aGk=", Encoding::Base64).unwrap(), b"hi");
+    assert_eq!(decode("hi", Encoding::Utf8).unwrap(), b"hi");
+    assert!(decode("not base64!", Encoding::Base64).is_err());
+    assert_eq!(encode(&[0xff, 0], Encoding::Base64), "/wA=");
+    assert_eq!(encode(b"hi", Encoding::Utf8), "hi");
+}
@@ -1,10 +1,11 @@
 use shared::ScriptMode;
 
 use crate::{
-    cmd::{CmdExe, CmdScript},
+    cmd::{CmdExe, CmdOutput, CmdScript, OutputSender},
     error::Error,
+    jobs::JobHandle,
 };
-use std::{path::PathBuf, process::Output, time::Duration};
+use std::{path::PathBuf, time::Duration};
 
 pub async fn install_choco() -> Result<(), Error> {
     let response = reqwest::get("https://chocolatey.org/install.ps1").await?;
@@ -14,8 +15,14 @@ pub async fn install_choco() -> Result<(), Error> {
         mode: ScriptMode::PowerShell,
         args: vec![],
         env_vars: Default::default(),
-        detached: false,
+        detached: None,
         timeout: Duration::from_secs(999),
+        output: None,
+        job: None,
+        run_as_user: None,
+        stdin: None,
+        cwd: None,
+        max_output_bytes: None,
     }
     .run()
     .await?;
@@ -31,7 +38,11 @@ pub async fn install_choco() -> Result<(), Error> {
     Ok(())
 }
 
-pub async fn install_with_choco(name: String) -> Result<Output, Error> {
+pub async fn install_with_choco(
+    name: String,
+    output: Option<OutputSender>,
+    job: Option<JobHandle>,
+) -> Result<CmdOutput, Error> {
     let choco = PathBuf::from(env!("PROGRAMDATA"))
         .join("chocolatey")
         .join("bin")
@@ -39,8 +50,14 @@ pub async fn install_with_choco(name: String) -> Result<Output, Error> {
 
     let out = CmdExe {
         exe: choco,
-        detached: false,
+        detached: None,
         timeout: Duration::from_secs(1200),
+        output,
+        job,
+        run_as_user: None,
+        stdin: None,
+        cwd: None,
+        max_output_bytes: None,
         args: vec![
             "install",
             name.as_str(),
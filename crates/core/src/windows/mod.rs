@@ -2,7 +2,6 @@ pub mod choco;
 pub mod eventlog;
 pub mod svc;
 pub mod syscall;
-pub mod task;
 #[allow(clippy::upper_case_acronyms)]
 pub mod wmi;
 pub mod wua;
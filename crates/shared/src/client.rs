@@ -0,0 +1,225 @@
+//! Helpers for talking to agents from the server side.
+
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{ErrorCode, IronhiveError, IronhiveRequest, IronhiveRespond, AGENT_ID_HEADER};
+
+/// Reads the reply of an agent, either its response or the error it failed with.
+///
+/// Agents older than typed errors only set the `Nats-Service-Error` header, their errors
+/// come back with [`ErrorCode::Unknown`].
+pub fn parse_reply(msg: &async_nats::Message) -> Result<IronhiveRespond, IronhiveError> {
+    let service_error = msg
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get(async_nats::service::NATS_SERVICE_ERROR));
+
+    match service_error {
+        None => serde_json::from_slice(&msg.payload)
+            .map_err(|e| IronhiveError::new(ErrorCode::Internal, format!("invalid reply: {e}"))),
+        Some(error) => Err(serde_json::from_slice(&msg.payload)
+            .unwrap_or_else(|_| IronhiveError::new(ErrorCode::Unknown, error.as_str()))),
+    }
+}
+
+/// Which agents a group request goes to and how long to wait for their replies.
+#[derive(Debug, Clone)]
+pub struct GroupTarget {
+    pub group: String,
+    /// The `subject_prefix` of the agents, if they have one.
+    pub subject_prefix: Option<String>,
+    /// Stop waiting for replies after this long.
+    pub timeout: Duration,
+    /// Stop waiting once this many agents replied, like when the size of the group is known.
+    pub expected: Option<usize>,
+}
+
+impl GroupTarget {
+    pub fn new(group: impl Into<String>) -> Self {
+        Self {
+            group: group.into(),
+            subject_prefix: None,
+            timeout: Duration::from_secs(5),
+            expected: None,
+        }
+    }
+
+    pub fn subject(&self) -> String {
+        crate::group_subject(self.subject_prefix.as_deref(), &self.group)
+    }
+}
+
+/// The reply of one agent to a group request.
+#[derive(Debug)]
+pub struct GroupReply {
+    pub agent_id: String,
+    pub result: Result<IronhiveRespond, IronhiveError>,
+}
+
+/// Sends `request` to every agent of a group and collects their replies until the timeout of
+/// `target` passes.
+pub async fn request_group(
+    client: &async_nats::Client,
+    target: &GroupTarget,
+    request: &IronhiveRequest,
+) -> Result<Vec<GroupReply>, async_nats::Error> {
+    let inbox = client.new_inbox();
+    let mut replies = client.subscribe(inbox.clone()).await?;
+    client
+        .publish_with_reply(target.subject(), inbox, request.as_bytes())
+        .await?;
+    client.flush().await?;
+
+    let deadline = tokio::time::Instant::now() + target.timeout;
+    let mut collected = Vec::new();
+    loop {
+        if let Some(expected) = target.expected {
+            if collected.len() >= expected {
+                break;
+            }
+        }
+        let msg = match tokio::time::timeout_at(deadline, replies.next()).await {
+            Ok(Some(msg)) => msg,
+            _ => break,
+        };
+        let agent_id = msg
+            .headers
+            .as_ref()
+            .and_then(|headers| headers.get(AGENT_ID_HEADER));
+        match agent_id {
+            Some(agent_id) => collected.push(GroupReply {
+                agent_id: agent_id.as_str().to_string(),
+                result: parse_reply(&msg),
+            }),
+            None => tracing::debug!("group reply without agent id: {msg:?}"),
+        }
+    }
+    let _ = replies.unsubscribe().await;
+
+    Ok(collected)
+}
+
+/// Where a tunnel leads, as seen from the agent.
+#[derive(Debug, Clone)]
+pub struct TunnelTarget {
+    pub agent_id: String,
+    /// The `subject_prefix` of the agent, if it has one.
+    pub subject_prefix: Option<String>,
+    pub host: String,
+    pub port: u16,
+    pub idle_timeout: Duration,
+}
+
+impl TunnelTarget {
+    pub fn new(agent_id: impl Into<String>, host: impl Into<String>, port: u16) -> Self {
+        Self {
+            agent_id: agent_id.into(),
+            subject_prefix: None,
+            host: host.into(),
+            port,
+            idle_timeout: crate::default_idle_timeout(),
+        }
+    }
+
+    /// The subject of the agent the tunnel is opened on.
+    pub fn subject(&self) -> String {
+        crate::agent_subject(self.subject_prefix.as_deref(), &self.agent_id)
+    }
+}
+
+/// Opens a tunnel to `target` for every connection accepted on `listener`, exposing a port
+/// reachable from an agent as a local port.
+///
+/// Only returns if accepting fails, a failing tunnel just closes its connection.
+pub async fn forward_tunnel(
+    client: async_nats::Client,
+    listener: TcpListener,
+    target: TunnelTarget,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let client = client.clone();
+        let target = target.clone();
+        tokio::spawn(async move {
+            if let Err(e) = bridge_tunnel(&client, stream, &target).await {
+                tracing::error!("tunnel for {peer} failed: {e:?}");
+            }
+        });
+    }
+}
+
+/// Bridges `stream` to `target` through a tunnel opened on the agent, until either side
+/// closes it.
+pub async fn bridge_tunnel(
+    client: &async_nats::Client,
+    mut stream: TcpStream,
+    target: &TunnelTarget,
+) -> Result<(), async_nats::Error> {
+    let request = IronhiveRequest::OpenTunnel {
+        target_host: target.host.clone(),
+        target_port: target.port,
+        idle_timeout: target.idle_timeout,
+    };
+    let reply = client.request(target.subject(), request.as_bytes()).await?;
+    let (tunnel_id, input_subject, output_subject) = match parse_reply(&reply)? {
+        IronhiveRespond::TunnelOpened {
+            tunnel_id,
+            input_subject,
+            output_subject,
+            ..
+        } => (tunnel_id, input_subject, output_subject),
+        resp => return Err(format!("unexpected reply: {resp:?}").into()),
+    };
+
+    let mut output = client.subscribe(output_subject).await?;
+    // Subscribed, so the agent can start relaying what the target sends.
+    client
+        .publish(input_subject.clone(), bytes::Bytes::new())
+        .await?;
+
+    let (mut reader, mut writer) = stream.split();
+    let mut buf = vec![0; 32 * 1024];
+    let mut reading = true;
+
+    let res: Result<(), async_nats::Error> = async {
+        loop {
+            tokio::select! {
+                read = reader.read(&mut buf), if reading => match read? {
+                    0 => {
+                        // Tell the agent there is nothing more, but keep relaying its output.
+                        client.publish(input_subject.clone(), bytes::Bytes::new()).await?;
+                        reading = false;
+                    }
+                    n => {
+                        let data = bytes::Bytes::copy_from_slice(&buf[..n]);
+                        client.publish(input_subject.clone(), data).await?;
+                    }
+                },
+                msg = output.next() => match msg {
+                    Some(msg) if !msg.payload.is_empty() => writer.write_all(&msg.payload).await?,
+                    // The tunnel is over.
+                    _ => return Ok(()),
+                },
+            }
+        }
+    }
+    .await;
+
+    let _ = writer.shutdown().await;
+    if res.is_err() {
+        let _ = client
+            .request(
+                target.subject(),
+                IronhiveRequest::CloseTunnel { tunnel_id }.as_bytes(),
+            )
+            .await;
+    }
+
+    res
+}
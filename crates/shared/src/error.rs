@@ -0,0 +1,82 @@
+/// What kind of failure an [`IronhiveError`] is, stable across agent versions.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "server", derive(serde::Serialize))]
+#[cfg_attr(feature = "client", derive(serde::Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The process, job, file, session or other thing the request names doesn't exist.
+    NotFound,
+    /// The request is malformed or its values are out of range.
+    InvalidRequest,
+    /// Not supported on this agent's OS or build, see the `Capabilities` request.
+    Unsupported,
+    /// Denied by the OS or the agent's configuration.
+    PermissionDenied,
+    Timeout,
+    /// The request doesn't match the agent's state, like an upload chunk at the wrong offset.
+    Conflict,
+    /// Data didn't match its checksum or size.
+    VerifyFailed,
+    /// A command or script the agent ran failed.
+    CommandFailed,
+    /// Talking to NATS or another network service failed.
+    Network,
+    /// The agent is handling too many requests, try again later.
+    Busy,
+    Internal,
+    /// A code this version doesn't know yet.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// A HTTP like status, as used by the NATS service API.
+    pub fn status(&self) -> u16 {
+        match self {
+            ErrorCode::NotFound => 404,
+            ErrorCode::InvalidRequest => 400,
+            ErrorCode::Unsupported => 501,
+            ErrorCode::PermissionDenied => 403,
+            ErrorCode::Timeout => 504,
+            ErrorCode::Conflict => 409,
+            ErrorCode::VerifyFailed => 422,
+            ErrorCode::CommandFailed | ErrorCode::Internal | ErrorCode::Unknown => 500,
+            ErrorCode::Network => 502,
+            ErrorCode::Busy => 503,
+        }
+    }
+}
+
+/// The body of an error reply, sent with the `Nats-Service-Error` header set.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "server", derive(serde::Serialize))]
+#[cfg_attr(feature = "client", derive(serde::Deserialize))]
+pub struct IronhiveError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Sending the same request again may succeed.
+    #[serde(default)]
+    pub retryable: bool,
+    /// Values specific to the error, like the expected offset of a `Conflict`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl IronhiveError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retryable: false,
+            details: None,
+        }
+    }
+}
+
+impl std::fmt::Display for IronhiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for IronhiveError {}
@@ -2,24 +2,60 @@ use std::time::Duration;
 
 use bytes::Bytes;
 
+#[cfg(feature = "client")]
+pub mod client;
+mod error;
 mod message;
 mod request;
 mod respond;
 
+pub use error::*;
 pub use message::*;
 pub use request::*;
 pub use respond::*;
 
+/// Version of the request and response format, bumped on changes older servers or agents
+/// can't handle.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Header carrying the id of the agent that replied to a group request.
+pub const AGENT_ID_HEADER: &str = "Ironhive-Agent-Id";
+
+/// The subject agent `agent_id` takes requests on, below its `subject_prefix` if it has one.
+pub fn agent_subject(subject_prefix: Option<&str>, agent_id: &str) -> String {
+    match subject_prefix {
+        Some(prefix) => format!("{prefix}.{agent_id}"),
+        None => agent_id.to_string(),
+    }
+}
+
+/// The subject every agent in `group` takes requests on, below the agents' `subject_prefix`
+/// if they have one.
+pub fn group_subject(subject_prefix: Option<&str>, group: &str) -> String {
+    match subject_prefix {
+        Some(prefix) => format!("{prefix}.group.{group}"),
+        None => format!("group.{group}"),
+    }
+}
+
 fn default_timeout() -> Duration {
     Duration::from_secs(15)
 }
 
-/// # Safety
-/// Ensure coverage of unit tests
-pub unsafe fn as_bytes<S: serde::Serialize>(s: &S) -> Bytes {
+fn default_idle_timeout() -> Duration {
+    Duration::from_secs(15 * 60)
+}
+
+fn default_list_limit() -> usize {
+    500
+}
+
+/// # Panics
+/// If `s` can't be serialized, like a path that isn't valid UTF-8.
+pub fn as_bytes<S: serde::Serialize>(s: &S) -> Bytes {
     use bytes::BufMut;
     let mut writer = bytes::BytesMut::new().writer();
 
-    serde_json::to_writer(&mut writer, s).unwrap_unchecked();
+    serde_json::to_writer(&mut writer, s).expect("serialize message");
     writer.into_inner().freeze()
 }
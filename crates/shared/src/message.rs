@@ -1,4 +1,8 @@
-use std::{collections::HashMap, path::PathBuf, time::Duration};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
 
 use serde::Deserialize;
 use serde::Serialize;
@@ -91,6 +95,16 @@ pub struct PublicIPNats {
     pub public_ip: String,
 }
 
+/// Published by an agent shutting down, with `agent-offline` as its reply subject like the
+/// check-ins.
+#[derive(Debug, PartialEq, Default, Clone)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+pub struct AgentOfflineNats {
+    pub agent_id: String,
+    pub version: String,
+}
+
 #[derive(Debug, PartialEq, Default, Clone)]
 #[cfg_attr(feature = "server", derive(Serialize))]
 #[cfg_attr(feature = "client", derive(Deserialize))]
@@ -136,6 +150,189 @@ pub struct RunScriptResp {
     pub id: i32,
 }
 
+/// A command execution currently running on the agent.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+pub struct JobInfo {
+    pub id: u64,
+    /// Short description of the request that started the job.
+    pub request: String,
+    pub pid: Option<u32>,
+    #[serde(with = "humantime_serde")]
+    pub start_time: SystemTime,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Why a command stopped running.
+#[derive(Debug, PartialEq, Eq, Default, Clone, Copy)]
+#[cfg_attr(
+    any(feature = "server", feature = "client"),
+    derive(Deserialize, Serialize)
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Termination {
+    /// It exited on its own, with `retcode`.
+    #[default]
+    Exited,
+    /// It was killed by a signal it did not handle, Unix only.
+    Signaled,
+    /// It ran past its timeout and the agent killed it.
+    TimedOut,
+    /// Its job was cancelled.
+    Cancelled,
+    /// It could not be started at all.
+    NotStarted,
+}
+
+/// How binary data is carried in a json string.
+#[derive(Debug, PartialEq, Eq, Default, Clone, Copy)]
+#[cfg_attr(
+    any(feature = "server", feature = "client"),
+    derive(Deserialize, Serialize)
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+/// Data written to the stdin of a command, which is closed afterwards.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(
+    any(feature = "server", feature = "client"),
+    derive(Deserialize, Serialize)
+)]
+pub struct StdinData {
+    pub data: String,
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+/// A chunk of output published to the stream subject of a running command.
+///
+/// `seq` starts at 0 and increases by one for every chunk of the same execution.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+pub struct StreamChunkNats {
+    pub seq: u64,
+    pub stream: OutputStream,
+    pub data: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+/// A file system entry, symlinks are described themselves rather than what they point to.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+pub struct FileEntry {
+    pub name: String,
+    pub kind: FileKind,
+    pub size: u64,
+    /// Permission bits, Unix only.
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Name of the owning user, Unix only.
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default, with = "humantime_serde")]
+    pub modified: Option<SystemTime>,
+    /// Lossily converted, a target that isn't valid UTF-8 has its invalid bytes replaced.
+    #[serde(default)]
+    pub symlink_target: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub enum ShellCloseReason {
+    /// The shell exited on its own.
+    Exited,
+    /// Closed by a `CloseShell` request.
+    Closed,
+    /// Nothing was read or written for the idle timeout of the session.
+    IdleTimeout,
+    /// The agent is shutting down.
+    Shutdown,
+}
+
+/// Published to the closed subject of a shell session once it is over.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+pub struct ShellClosedNats {
+    pub session_id: String,
+    pub reason: ShellCloseReason,
+    /// Exit code of the shell, 128 plus the signal if it was killed by one.
+    pub retcode: Option<i32>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelCloseReason {
+    /// The target closed the connection.
+    TargetClosed,
+    /// Closed by a `CloseTunnel` request.
+    Closed,
+    /// Nothing was sent either way for the idle timeout of the tunnel.
+    IdleTimeout,
+    /// Reading from or writing to the target failed.
+    Error,
+    /// The agent is shutting down.
+    Shutdown,
+}
+
+/// Published to the closed subject of a tunnel once it is over.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+pub struct TunnelClosedNats {
+    pub tunnel_id: String,
+    pub reason: TunnelCloseReason,
+}
+
+/// A chunk of a file published to the subject of a `DownloadFile` request.
+///
+/// `seq` starts at 0 and increases by one for every chunk, the last one has `last` set and
+/// carries the sha256 of all the data sent.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+pub struct FileChunkNats {
+    pub seq: u64,
+    /// Where `data` starts in the file.
+    pub offset: u64,
+    /// Base64 encoded.
+    pub data: String,
+    #[serde(default)]
+    pub last: bool,
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
 #[derive(Debug, PartialEq, Default, Clone)]
 #[cfg_attr(feature = "server", derive(Serialize))]
 #[cfg_attr(feature = "client", derive(serde::Deserialize))]
@@ -155,8 +352,10 @@ pub struct WUAPackage {
 }
 
 #[derive(Debug, PartialEq, Default, Clone)]
-#[cfg_attr(feature = "server", derive(Serialize))]
-#[cfg_attr(feature = "client", derive(serde::Deserialize))]
+#[cfg_attr(
+    any(feature = "server", feature = "client"),
+    derive(Deserialize, Serialize)
+)]
 pub struct AutomatedTask {
     pub id: i32,
     pub task_actions: Vec<TaskAction>,
@@ -165,25 +364,105 @@ pub struct AutomatedTask {
 }
 
 #[derive(Debug, PartialEq, Clone)]
-#[cfg_attr(feature = "server", derive(Serialize))]
-#[cfg_attr(feature = "client", derive(serde::Deserialize))]
+#[cfg_attr(
+    any(feature = "server", feature = "client"),
+    derive(Deserialize, Serialize)
+)]
 pub enum TaskAction {
     CmdScript {
         code: String,
+        #[serde(default)]
         mode: ScriptMode,
+        #[serde(default)]
         args: Vec<String>,
+        #[serde(default)]
         env_vars: HashMap<String, String>,
+        #[serde(default)]
         detached: bool,
+        #[serde(with = "humantime_serde")]
+        #[serde(default = "crate::default_timeout")]
         timeout: Duration,
     },
     CmdShell {
         shell: String,
         command: String,
+        #[serde(default)]
         detached: bool,
+        #[serde(with = "humantime_serde")]
+        #[serde(default = "crate::default_timeout")]
         timeout: Duration,
     },
 }
 
+/// The result of a single [`TaskAction`].
+#[derive(Debug, PartialEq, Default, Clone)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(serde::Deserialize))]
+pub struct TaskActionResult {
+    pub stdout: String,
+    pub stderr: String,
+    /// The exit code, or 128 plus the signal like shells report it, `None` if the action
+    /// could not be started.
+    pub retcode: Option<i32>,
+    /// The signal that terminated the action, Unix only.
+    #[serde(default)]
+    pub signal: Option<i32>,
+    #[serde(default)]
+    pub signal_name: Option<String>,
+    #[serde(default)]
+    pub core_dumped: bool,
+    #[serde(default)]
+    pub termination: Termination,
+    #[serde(with = "humantime_serde")]
+    pub execution_time: Duration,
+    /// The action ran past its timeout and was killed.
+    #[serde(default)]
+    pub timed_out: bool,
+    /// Why the action could not be started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<crate::IronhiveError>,
+}
+
+/// An [`AutomatedTask`] that the agent runs on its own, following `schedule`.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(
+    any(feature = "server", feature = "client"),
+    derive(Deserialize, Serialize)
+)]
+pub struct ScheduledTask {
+    /// Unique name of the scheduled task, used to replace or delete it.
+    pub name: String,
+    pub schedule: Schedule,
+    pub task: AutomatedTask,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(
+    any(feature = "server", feature = "client"),
+    derive(Deserialize, Serialize)
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Schedule {
+    /// A cron expression including seconds, e.g. `0 */5 * * * *`, evaluated in UTC.
+    Cron { expr: String },
+    /// Runs the task every `every`, the first run happens one interval after scheduling.
+    Interval {
+        #[serde(with = "humantime_serde")]
+        every: Duration,
+    },
+}
+
+/// Published after every run of a [`ScheduledTask`].
+#[derive(Debug, PartialEq, Default, Clone)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+pub struct SchedTaskResultNats {
+    pub agent_id: String,
+    pub name: String,
+    pub id: i32,
+    pub results: Vec<TaskActionResult>,
+}
+
 #[derive(Debug, PartialEq, Default, Clone)]
 #[cfg_attr(
     any(feature = "server", feature = "client"),
@@ -196,17 +475,27 @@ pub enum ScriptMode {
         ext: String,
     },
     Cmd,
+    /// Run the file itself, following its shebang line on Unix.
     #[default]
     Directly,
+    Bash,
+    Sh,
+    Python,
+    /// PowerShell 7+, also available on Linux and macOS.
+    Pwsh,
+    Perl,
 }
 
 impl ScriptMode {
     pub fn ext(&self) -> &str {
         match self {
-            ScriptMode::PowerShell => ".ps1",
+            ScriptMode::PowerShell | ScriptMode::Pwsh => ".ps1",
             ScriptMode::Binary { ext, .. } => ext.as_str(),
             ScriptMode::Cmd => ".bat",
             ScriptMode::Directly => "",
+            ScriptMode::Bash | ScriptMode::Sh => ".sh",
+            ScriptMode::Python => ".py",
+            ScriptMode::Perl => ".pl",
         }
     }
 }
@@ -1,8 +1,8 @@
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
 use crate::{
-    default_timeout,
-    message::{AgentMode, ScriptMode},
+    default_idle_timeout, default_list_limit, default_timeout,
+    message::{AgentMode, AutomatedTask, Encoding, ScheduledTask, ScriptMode, StdinData},
 };
 
 #[derive(Debug, PartialEq, Clone)]
@@ -12,12 +12,18 @@ use crate::{
 #[serde(tag = "func")]
 pub enum IronhiveRequest {
     Ping,
+    /// What this agent supports, see `IronhiveRespond::Capabilities`.
+    Capabilities,
     PatchMgmt {
         patch_mgmnt: bool,
     },
-    // SchedTask,
-    // DelSchedTask,
-    // ListSchedTasks,
+    SchedTask {
+        task: ScheduledTask,
+    },
+    DelSchedTask {
+        name: String,
+    },
+    ListSchedTasks,
     // EventLog,
     Procs,
     KillProc {
@@ -29,7 +35,30 @@ pub enum IronhiveRequest {
         #[serde(with = "humantime_serde")]
         #[serde(default = "default_timeout")]
         timeout: Duration,
-        // run_as_user: bool,
+        /// Run as this user instead of the agent's own, Unix only.
+        #[serde(default)]
+        run_as_user: Option<String>,
+        /// Publish output chunks to this subject while the command runs.
+        #[serde(default)]
+        stream_subject: Option<String>,
+        /// Start the command in the background and reply with its pid and log files right away.
+        #[serde(default)]
+        detached: bool,
+        /// Written to the stdin of the command.
+        #[serde(default)]
+        stdin: Option<StdinData>,
+        /// Working directory, the agent's own if unset.
+        #[serde(default)]
+        cwd: Option<PathBuf>,
+        /// Keep at most this many bytes of each of stdout and stderr, dropping the middle.
+        #[serde(default)]
+        max_output_bytes: Option<usize>,
+        /// How stdout and stderr are encoded in the reply.
+        #[serde(default)]
+        encoding: Encoding,
+        /// Reply with [`crate::IronhiveRespond::RawCMDOutput`] instead of `RawCMDResp`.
+        #[serde(default)]
+        structured: bool,
     },
     WinServices,
     WinSvcDetail {
@@ -52,10 +81,30 @@ pub enum IronhiveRequest {
         #[serde(with = "humantime_serde")]
         #[serde(default = "default_timeout")]
         timeout: Duration,
-        // run_as_user: bool,
+        /// Run as this user instead of the agent's own, Unix only.
+        #[serde(default)]
+        run_as_user: Option<String>,
         #[serde(default)]
         env_vars: HashMap<String, String>,
         id: i32,
+        /// Publish output chunks to this subject while the script runs.
+        #[serde(default)]
+        stream_subject: Option<String>,
+        /// Start the script in the background and reply with its pid and log files right away.
+        #[serde(default)]
+        detached: bool,
+        /// Written to the stdin of the script.
+        #[serde(default)]
+        stdin: Option<StdinData>,
+        /// Working directory, the agent's own if unset.
+        #[serde(default)]
+        cwd: Option<PathBuf>,
+        /// Keep at most this many bytes of each of stdout and stderr, dropping the middle.
+        #[serde(default)]
+        max_output_bytes: Option<usize>,
+        /// How stdout and stderr are encoded in the reply.
+        #[serde(default)]
+        encoding: Encoding,
     },
     SoftwareList,
     RebootNow,
@@ -65,12 +114,131 @@ pub enum IronhiveRequest {
     CpuLoadAvg,
     CpuUssage,
     // RunChecks,
-    // RunTask,
+    RunTask {
+        task: AutomatedTask,
+    },
     PublicIp,
+    ListJobs,
+    CancelJob {
+        id: u64,
+    },
+    /// Starts a chunked upload to `path`, or resumes it when `upload_id` is already known.
+    UploadStart {
+        upload_id: String,
+        path: PathBuf,
+        size: u64,
+        /// Hex sha256 of the whole file.
+        sha256: String,
+        /// Permission bits of the file, Unix only.
+        #[serde(default)]
+        mode: Option<u32>,
+        /// User owning the file, Unix only.
+        #[serde(default)]
+        owner: Option<String>,
+    },
+    UploadChunk {
+        upload_id: String,
+        offset: u64,
+        /// Base64 encoded.
+        data: String,
+    },
+    /// Verifies the upload and moves it into place.
+    UploadFinish {
+        upload_id: String,
+    },
+    /// Lists the entries of a directory sorted by name, `limit` of them starting at `offset`.
+    ListDir {
+        path: PathBuf,
+        #[serde(default)]
+        offset: usize,
+        #[serde(default = "default_list_limit")]
+        limit: usize,
+    },
+    Stat {
+        path: PathBuf,
+    },
+    /// Reads up to `len` bytes from `offset`, the agent caps `len` to keep the reply small.
+    ReadFileRange {
+        path: PathBuf,
+        #[serde(default)]
+        offset: u64,
+        len: u64,
+        /// How the data is encoded in the reply.
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    /// Starts an interactive shell on a PTY, Unix only.
+    ///
+    /// Its input and output are relayed as raw bytes over the subjects of the reply.
+    OpenShell {
+        /// The agent user's login shell if unset.
+        #[serde(default)]
+        shell: Option<String>,
+        cols: u16,
+        rows: u16,
+        /// Close the session once nothing was read or written for this long.
+        #[serde(with = "humantime_serde")]
+        #[serde(default = "default_idle_timeout")]
+        idle_timeout: Duration,
+    },
+    ResizeShell {
+        session_id: String,
+        cols: u16,
+        rows: u16,
+    },
+    CloseShell {
+        session_id: String,
+    },
+    /// Connects to `target_host:target_port` from the agent and bridges the connection to the
+    /// subjects of the reply.
+    OpenTunnel {
+        target_host: String,
+        target_port: u16,
+        /// Close the tunnel once nothing was sent either way for this long.
+        #[serde(with = "humantime_serde")]
+        #[serde(default = "default_idle_timeout")]
+        idle_timeout: Duration,
+    },
+    CloseTunnel {
+        tunnel_id: String,
+    },
+    /// Replaces the content of a file atomically.
+    WriteFile {
+        path: PathBuf,
+        content: String,
+        /// How `content` is encoded.
+        #[serde(default)]
+        encoding: Encoding,
+        /// Permission bits, the file keeps its current ones if unset. Unix only.
+        #[serde(default)]
+        mode: Option<u32>,
+        /// Keep the previous content as a timestamped backup next to the file.
+        #[serde(default)]
+        backup: bool,
+    },
+    /// Rolls a file back to a backup made by `WriteFile`, the latest one if `backup_path` is unset.
+    RestoreBackup {
+        path: PathBuf,
+        #[serde(default)]
+        backup_path: Option<PathBuf>,
+    },
+    /// Publishes the file in chunks to `subject`, replies once all of them are sent.
+    DownloadFile {
+        path: PathBuf,
+        subject: String,
+        #[serde(default)]
+        offset: u64,
+        /// Send at most this many bytes, up to the end of the file if unset.
+        #[serde(default)]
+        max_len: Option<u64>,
+    },
     // InstallPython,
     InstallChoco,
     InstallWithChoco {
         choco_prog_name: String,
+        /// Publish output chunks to this subject while choco runs.
+        #[serde(default)]
+        stream_subject: Option<String>,
     },
     GetWinUpdates,
     InstallWinUpdates {
@@ -83,9 +251,74 @@ pub enum IronhiveRequest {
     },
 }
 
+/// Generates [`IronhiveRequest::FUNCTIONS`] and [`IronhiveRequest::func`] from one list, so
+/// the match on every request keeps the list complete.
+macro_rules! functions {
+    ($($variant:ident $({ $($fields:tt)* })? => $func:literal,)*) => {
+        impl IronhiveRequest {
+            /// The `func` of every request, in declaration order.
+            pub const FUNCTIONS: &'static [&'static str] = &[$($func),*];
+
+            /// The `func` this request is tagged with.
+            pub fn func(&self) -> &'static str {
+                match self {
+                    $(Self::$variant $({ $($fields)* })? => $func,)*
+                }
+            }
+        }
+    };
+}
+
+functions! {
+    Ping => "ping",
+    Capabilities => "capabilities",
+    PatchMgmt { .. } => "patchmgmt",
+    SchedTask { .. } => "schedtask",
+    DelSchedTask { .. } => "delschedtask",
+    ListSchedTasks => "listschedtasks",
+    Procs => "procs",
+    KillProc { .. } => "killproc",
+    RawCmd { .. } => "rawcmd",
+    WinServices => "winservices",
+    WinSvcDetail { .. } => "winsvcdetail",
+    WinSvcAction { .. } => "winsvcaction",
+    EditWinSvc { .. } => "editwinsvc",
+    RunScript { .. } => "runscript",
+    SoftwareList => "softwarelist",
+    RebootNow => "rebootnow",
+    NeedsReboot => "needsreboot",
+    SysInfo => "sysinfo",
+    WMI => "wmi",
+    CpuLoadAvg => "cpuloadavg",
+    CpuUssage => "cpuussage",
+    RunTask { .. } => "runtask",
+    PublicIp => "publicip",
+    ListJobs => "listjobs",
+    CancelJob { .. } => "canceljob",
+    UploadStart { .. } => "uploadstart",
+    UploadChunk { .. } => "uploadchunk",
+    UploadFinish { .. } => "uploadfinish",
+    ListDir { .. } => "listdir",
+    Stat { .. } => "stat",
+    ReadFileRange { .. } => "readfilerange",
+    OpenShell { .. } => "openshell",
+    ResizeShell { .. } => "resizeshell",
+    CloseShell { .. } => "closeshell",
+    OpenTunnel { .. } => "opentunnel",
+    CloseTunnel { .. } => "closetunnel",
+    WriteFile { .. } => "writefile",
+    RestoreBackup { .. } => "restorebackup",
+    DownloadFile { .. } => "downloadfile",
+    InstallChoco => "installchoco",
+    InstallWithChoco { .. } => "installwithchoco",
+    GetWinUpdates => "getwinupdates",
+    InstallWinUpdates { .. } => "installwinupdates",
+    Checkin { .. } => "checkin",
+}
+
 impl IronhiveRequest {
     #[cfg(feature = "client")]
     pub fn as_bytes(&self) -> bytes::Bytes {
-        unsafe { crate::as_bytes(self) }
+        crate::as_bytes(self)
     }
 }
@@ -1,6 +1,9 @@
-use std::time::Duration;
+use std::{path::PathBuf, time::Duration};
 
-use crate::message::{ProcessMsg, WUAPackage, WinSoftwareList, WindowsService};
+use crate::message::{
+    Encoding, FileEntry, JobInfo, ProcessMsg, ScheduledTask, TaskActionResult, Termination,
+    WUAPackage, WinSoftwareList, WindowsService,
+};
 
 #[derive(Debug, PartialEq, Clone)]
 #[cfg_attr(feature = "server", derive(serde::Serialize))]
@@ -9,20 +12,185 @@ use crate::message::{ProcessMsg, WUAPackage, WinSoftwareList, WindowsService};
 #[serde(tag = "resp")]
 pub enum IronhiveRespond {
     Pong,
+    Capabilities {
+        version: String,
+        /// Like `linux` or `windows`.
+        os: String,
+        /// Like `x86_64` or `aarch64`.
+        arch: String,
+        protocol_version: u32,
+        /// The `func` of every request this agent handles.
+        functions: Vec<String>,
+        /// The script modes whose interpreter this agent has, by name.
+        script_modes: Vec<String>,
+    },
     ProcessMsg {
         msgs: Vec<ProcessMsg>,
     },
     Ok,
     RawCMDResp {
         results: String,
+        /// The command ran past its timeout and was killed, `results` is what it printed until then.
+        #[serde(default)]
+        timed_out: bool,
+        /// The middle of the output was dropped to stay within `max_output_bytes`.
+        #[serde(default)]
+        truncated: bool,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    /// Everything a raw command left behind, replied when `structured` is set.
+    RawCMDOutput {
+        stdout: String,
+        stderr: String,
+        /// The exit code, or 128 plus the signal number if it was killed by a signal.
+        retcode: i32,
+        /// The signal that terminated the command, Unix only.
+        signal: Option<i32>,
+        /// Like `SIGSEGV`.
+        #[serde(default)]
+        signal_name: Option<String>,
+        #[serde(default)]
+        core_dumped: bool,
+        #[serde(default)]
+        termination: Termination,
+        #[serde(with = "humantime_serde")]
+        execution_time: Duration,
+        #[serde(default)]
+        timed_out: bool,
+        #[serde(default)]
+        truncated: bool,
+        #[serde(default)]
+        encoding: Encoding,
     },
     RunScriptResp {
         stdout: String,
         stderr: String,
+        /// The exit code, or 128 plus the signal number if it was killed by a signal.
         retcode: i32,
+        /// The signal that terminated the script, Unix only.
+        #[serde(default)]
+        signal: Option<i32>,
+        /// Like `SIGSEGV`.
+        #[serde(default)]
+        signal_name: Option<String>,
+        #[serde(default)]
+        core_dumped: bool,
+        #[serde(default)]
+        termination: Termination,
         #[serde(with = "humantime_serde")]
         execution_time: Duration,
         id: i32,
+        /// The script ran past its timeout and was killed, the output is what it printed until then.
+        #[serde(default)]
+        timed_out: bool,
+        /// The middle of stdout or stderr was dropped to stay within `max_output_bytes`.
+        #[serde(default)]
+        truncated: bool,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    /// A command started in detached mode, it keeps running on its own.
+    Detached {
+        pid: u32,
+        stdout_path: PathBuf,
+        stderr_path: PathBuf,
+    },
+    Jobs {
+        jobs: Vec<JobInfo>,
+    },
+    JobCancelled {
+        id: u64,
+        stdout: String,
+        stderr: String,
+    },
+    /// How many bytes of upload `upload_id` the agent has, the next chunk starts there.
+    UploadStatus {
+        upload_id: String,
+        received: u64,
+    },
+    UploadDone {
+        path: PathBuf,
+        size: u64,
+    },
+    DirEntries {
+        path: PathBuf,
+        entries: Vec<FileEntry>,
+        /// Number of entries in the whole directory.
+        total: usize,
+        /// Offset of the next page, none once this is the last one.
+        next_offset: Option<usize>,
+    },
+    FileStat {
+        path: PathBuf,
+        entry: FileEntry,
+    },
+    FileRange {
+        path: PathBuf,
+        offset: u64,
+        data: String,
+        encoding: Encoding,
+        /// Size of the whole file.
+        size: u64,
+        /// The range reaches the end of the file.
+        eof: bool,
+    },
+    ShellOpened {
+        session_id: String,
+        pid: u32,
+        /// Publish input for the shell here.
+        ///
+        /// The agent only relays the shell's output after the first message here, which may
+        /// be empty, so subscribe to `output_subject` before sending it.
+        input_subject: String,
+        /// The shell's output is published here.
+        output_subject: String,
+        /// A `ShellClosedNats` is published here when the session ends.
+        closed_subject: String,
+    },
+    TunnelOpened {
+        tunnel_id: String,
+        /// Publish bytes for the target here, an empty message closes the writing half.
+        ///
+        /// The agent only reads from the target after the first message here, which may be
+        /// empty, so subscribe to `output_subject` before sending it.
+        input_subject: String,
+        /// Bytes from the target are published here, an empty message marks the end.
+        output_subject: String,
+        /// A `TunnelClosedNats` is published here when the tunnel ends.
+        closed_subject: String,
+    },
+    FileWritten {
+        path: PathBuf,
+        size: u64,
+        /// None if the file didn't exist.
+        before_sha256: Option<String>,
+        after_sha256: String,
+        backup_path: Option<PathBuf>,
+    },
+    BackupRestored {
+        path: PathBuf,
+        backup_path: PathBuf,
+        before_sha256: Option<String>,
+        after_sha256: String,
+    },
+    FileDownloaded {
+        path: PathBuf,
+        /// Size of the whole file.
+        size: u64,
+        offset: u64,
+        /// Bytes sent, starting at `offset`.
+        len: u64,
+        /// Hex sha256 of the bytes sent.
+        sha256: String,
+        chunks: u64,
+    },
+    RunTaskResp {
+        id: i32,
+        results: Vec<TaskActionResult>,
+    },
+    SchedTasks {
+        tasks: Vec<ScheduledTask>,
     },
     NeedsReboot {
         needs: bool,
@@ -63,6 +231,6 @@ pub enum IronhiveRespond {
 impl IronhiveRespond {
     #[cfg(feature = "server")]
     pub fn as_bytes(&self) -> bytes::Bytes {
-        unsafe { crate::as_bytes(self) }
+        crate::as_bytes(self)
     }
 }
@@ -74,6 +74,12 @@ async fn main() -> anyhow::Result<()> {
 
                 let rpc = ironhive_core::Ironhive::new_with_options(agent, options).await?;
 
+                let shutdown = rpc.shutdown_handle();
+                tokio::spawn(async move {
+                    ironhive_core::shutdown_signal().await;
+                    shutdown.shutdown();
+                });
+
                 rpc.run().await?;
             }
             Commands::Env => {
@@ -24,6 +24,7 @@ async fn basic() {
     let req = tokio::spawn(async move {
         let msgs = [
             IronhiveRequest::Ping,
+            IronhiveRequest::Capabilities,
             IronhiveRequest::CpuLoadAvg,
             IronhiveRequest::CpuUssage,
             IronhiveRequest::NeedsReboot,
@@ -64,6 +65,10 @@ async fn basic() {
 
         check_resp!(
             IronhiveRespond::Pong,
+            IronhiveRespond::Capabilities {
+                protocol_version: ironhive_core::PROTOCOL_VERSION,
+                ..
+            },
             IronhiveRespond::CpuLoadAvg { .. },
             IronhiveRespond::CpuUssage { .. },
             IronhiveRespond::NeedsReboot { .. },
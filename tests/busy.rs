@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use ironhive_config::generate_agent_id;
+use ironhive_core::{
+    Agent, ErrorCode, Ironhive, IronhiveError, IronhiveRequest, IronhiveRespond, Limits,
+};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn busy() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let mut agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+    agent.limits = Limits {
+        max_concurrent: Some(1),
+        max_queued: 0,
+        ..Default::default()
+    };
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    let sleep = || IronhiveRequest::RawCmd {
+        shell: "sh".into(),
+        command: "sleep 3".into(),
+        timeout: Duration::from_secs(10),
+        stream_subject: None,
+        run_as_user: None,
+        detached: false,
+        stdin: None,
+        cwd: None,
+        max_output_bytes: None,
+        encoding: Default::default(),
+        structured: false,
+    };
+
+    let run = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let resp = client
+            .request(agent_id.to_string(), sleep().as_bytes())
+            .await
+            .unwrap();
+        serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap()
+    };
+
+    let busy = async {
+        tokio::time::sleep(Duration::from_secs(3)).await;
+
+        // Control requests still get through.
+        let resp = client
+            .request(agent_id.to_string(), IronhiveRequest::Ping.as_bytes())
+            .await
+            .unwrap();
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        assert_eq!(resp, IronhiveRespond::Pong);
+
+        let resp = client
+            .request(agent_id.to_string(), sleep().as_bytes())
+            .await
+            .unwrap();
+        let err = serde_json::from_slice::<IronhiveError>(&resp.payload).unwrap();
+        debug!("{err:?}");
+        assert_eq!(err.code, ErrorCode::Busy);
+        assert!(err.retryable);
+    };
+
+    let tasks = async {
+        let (resp, _) = tokio::join!(run, busy);
+        debug!("{resp:#?}");
+        assert!(matches!(resp, IronhiveRespond::RawCMDResp { .. }));
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
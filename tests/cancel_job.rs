@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use ironhive_config::generate_agent_id;
+use ironhive_core::{Agent, Ironhive, IronhiveRequest, IronhiveRespond};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn cancel_job() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    let run = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let resp = client
+            .request(
+                agent_id.to_string(),
+                IronhiveRequest::RawCmd {
+                    shell: "sh".into(),
+                    command: "echo started; sleep 60".into(),
+                    timeout: Duration::from_secs(120),
+                    stream_subject: None,
+                    run_as_user: None,
+                    detached: false,
+                    stdin: None,
+                    cwd: None,
+                    max_output_bytes: None,
+                    encoding: Default::default(),
+                    structured: false,
+                }
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap()
+    };
+
+    let cancel = async {
+        tokio::time::sleep(Duration::from_secs(3)).await;
+
+        let resp = client
+            .request(agent_id.to_string(), IronhiveRequest::ListJobs.as_bytes())
+            .await
+            .unwrap();
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        debug!("{resp:#?}");
+        let IronhiveRespond::Jobs { jobs } = resp else {
+            panic!("unexpected resp: {resp:?}");
+        };
+        assert_eq!(jobs.len(), 1);
+        assert!(jobs[0].pid.is_some());
+
+        let resp = client
+            .request(
+                agent_id.to_string(),
+                IronhiveRequest::CancelJob { id: jobs[0].id }.as_bytes(),
+            )
+            .await
+            .unwrap();
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        debug!("{resp:#?}");
+        if let IronhiveRespond::JobCancelled { stdout, .. } = resp {
+            assert_eq!(stdout.trim(), "started");
+        } else {
+            panic!("unexpected resp: {resp:?}");
+        }
+    };
+
+    let tasks = async {
+        let (resp, _) =
+            tokio::time::timeout(Duration::from_secs(30), async { tokio::join!(run, cancel) })
+                .await
+                .unwrap();
+        debug!("{resp:#?}");
+        assert!(matches!(resp, IronhiveRespond::RawCMDResp { .. }));
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
@@ -2,7 +2,7 @@ use std::time::Duration;
 
 use futures_util::StreamExt;
 use ironhive_config::generate_agent_id;
-use ironhive_core::{Agent, Ironhive, IronhiveRequest, IronhiveRespond};
+use ironhive_core::{Agent, Ironhive, IronhiveError, IronhiveRequest, IronhiveRespond};
 use sysinfo::SystemExt;
 use tracing::{debug, info};
 use tracing_test::traced_test;
@@ -60,16 +60,40 @@ async fn basic_command() {
                 shell: "cmd".into(),
                 command: "cargo --help".into(),
                 timeout: Duration::from_secs(2),
+                stream_subject: None,
+                run_as_user: None,
+                detached: false,
+                stdin: None,
+                cwd: None,
+                max_output_bytes: None,
+                encoding: Default::default(),
+                structured: false,
             },
             IronhiveRequest::RawCmd {
                 shell: "powershell".into(),
                 command: "cargo --help".into(),
                 timeout: Duration::from_secs(2),
+                stream_subject: None,
+                run_as_user: None,
+                detached: false,
+                stdin: None,
+                cwd: None,
+                max_output_bytes: None,
+                encoding: Default::default(),
+                structured: false,
             },
             IronhiveRequest::RawCmd {
                 shell: "bash".into(),
                 command: "cargo --help".into(),
                 timeout: Duration::from_secs(2),
+                stream_subject: None,
+                run_as_user: None,
+                detached: false,
+                stdin: None,
+                cwd: None,
+                max_output_bytes: None,
+                encoding: Default::default(),
+                structured: false,
             },
         ];
 
@@ -109,11 +133,10 @@ async fn basic_command() {
                         ));
                         $(assert!(logs_contain($slog));)?
                     }else {
-                        assert!(msg.payload.is_empty());
                         let headers = msg.headers.unwrap();
                         assert!(headers.get(async_nats::service::NATS_SERVICE_ERROR).is_some());
-                        let error = headers.get(async_nats::service::NATS_SERVICE_ERROR).unwrap();
-                        debug!("{}",error.as_str())
+                        let error = serde_json::from_slice::<IronhiveError>(&msg.payload).unwrap();
+                        debug!("{error}")
                     }
 
                 )*
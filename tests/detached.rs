@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use ironhive_config::generate_agent_id;
+use ironhive_core::{Agent, Ironhive, IronhiveRequest, IronhiveRespond};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn detached_command() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+    let log_dir = agent.detached_log_dir();
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    let tasks = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let (shell, command) = {
+            #[cfg(windows)]
+            {
+                (
+                    "cmd",
+                    "echo started & ping -n 3 127.0.0.1 > nul & echo done 1>&2",
+                )
+            }
+            #[cfg(not(windows))]
+            {
+                ("sh", "echo started; sleep 2; echo done >&2")
+            }
+        };
+
+        let now = std::time::Instant::now();
+        let resp = client
+            .request(
+                agent_id.to_string(),
+                IronhiveRequest::RawCmd {
+                    shell: shell.into(),
+                    command: command.into(),
+                    timeout: Duration::from_secs(10),
+                    stream_subject: None,
+                    run_as_user: None,
+                    detached: true,
+                    stdin: None,
+                    cwd: None,
+                    max_output_bytes: None,
+                    encoding: Default::default(),
+                    structured: false,
+                }
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        debug!("{resp:#?}");
+
+        assert!(now.elapsed() < Duration::from_secs(2));
+        let IronhiveRespond::Detached {
+            pid,
+            stdout_path,
+            stderr_path,
+        } = resp
+        else {
+            panic!("unexpected resp: {resp:?}");
+        };
+        assert_ne!(pid, 0);
+        assert!(stdout_path.starts_with(&log_dir));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(&log_dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        tokio::time::sleep(Duration::from_secs(4)).await;
+
+        let stdout = std::fs::read_to_string(stdout_path).unwrap();
+        let stderr = std::fs::read_to_string(stderr_path).unwrap();
+        assert_eq!(stdout.trim(), "started");
+        assert_eq!(stderr.trim(), "done");
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use base64::Engine;
+use futures_util::StreamExt;
+use ironhive_config::generate_agent_id;
+use ironhive_core::{Agent, FileChunkNats, Ironhive, IronhiveRequest, IronhiveRespond};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn download_file() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    let path = std::env::temp_dir().join(format!("ironhive-download-{agent_id}.bin"));
+    let data = (0..200_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    tokio::fs::write(&path, &data).await.unwrap();
+
+    let tasks = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let mut chunks = client.subscribe("ironhive.download".into()).await.unwrap();
+
+        let resp = client
+            .request(
+                agent_id.to_string(),
+                IronhiveRequest::DownloadFile {
+                    path: path.clone(),
+                    subject: "ironhive.download".into(),
+                    offset: 0,
+                    max_len: None,
+                }
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        debug!("{resp:#?}");
+        let IronhiveRespond::FileDownloaded {
+            size, len, sha256, ..
+        } = resp
+        else {
+            panic!("unexpected resp: {resp:?}");
+        };
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(len, data.len() as u64);
+
+        let mut received = vec![];
+        let mut expected_seq = 0;
+        loop {
+            let raw_chunk = tokio::time::timeout(Duration::from_secs(5), chunks.next())
+                .await
+                .unwrap()
+                .unwrap();
+            let chunk = serde_json::from_slice::<FileChunkNats>(&raw_chunk.payload).unwrap();
+            assert_eq!(chunk.seq, expected_seq);
+            assert_eq!(chunk.offset, received.len() as u64);
+            expected_seq += 1;
+
+            received.extend(
+                base64::engine::general_purpose::STANDARD
+                    .decode(&chunk.data)
+                    .unwrap(),
+            );
+            if chunk.last {
+                assert_eq!(chunk.sha256, Some(sha256));
+                break;
+            }
+        }
+        assert_eq!(received, data);
+
+        let _ = tokio::fs::remove_file(&path).await;
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
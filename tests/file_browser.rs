@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use ironhive_config::generate_agent_id;
+use ironhive_core::{
+    Agent, ErrorCode, FileKind, Ironhive, IronhiveError, IronhiveRequest, IronhiveRespond,
+};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn file_browser() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let dir = std::env::temp_dir().join(format!("ironhive-browse-{agent_id}"));
+    let allowed = dir.join("allowed");
+    tokio::fs::create_dir_all(&allowed).await.unwrap();
+    for i in 0..5 {
+        tokio::fs::write(allowed.join(format!("{i}.log")), format!("log {i}"))
+            .await
+            .unwrap();
+    }
+
+    let mut agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+    agent.allowed_paths = vec![allowed.clone()];
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    let request = |req: IronhiveRequest| {
+        let client = client.clone();
+        let agent_id = agent_id.to_string();
+        async move { client.request(agent_id, req.as_bytes()).await.unwrap() }
+    };
+
+    let tasks = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let mut names = vec![];
+        let mut offset = Some(0);
+        while let Some(page) = offset {
+            let resp = request(IronhiveRequest::ListDir {
+                path: allowed.clone(),
+                offset: page,
+                limit: 2,
+            })
+            .await;
+            let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+            debug!("{resp:#?}");
+            let IronhiveRespond::DirEntries {
+                entries,
+                total,
+                next_offset,
+                ..
+            } = resp
+            else {
+                panic!("unexpected resp: {resp:?}");
+            };
+            assert_eq!(total, 5);
+            names.extend(entries.into_iter().map(|e| e.name));
+            offset = next_offset;
+        }
+        assert_eq!(names, ["0.log", "1.log", "2.log", "3.log", "4.log"]);
+
+        let resp = request(IronhiveRequest::Stat {
+            path: allowed.clone(),
+        })
+        .await;
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        if let IronhiveRespond::FileStat { entry, .. } = resp {
+            assert_eq!(entry.kind, FileKind::Dir);
+        } else {
+            panic!("unexpected resp: {resp:?}");
+        }
+
+        let resp = request(IronhiveRequest::ReadFileRange {
+            path: allowed.join("3.log"),
+            offset: 4,
+            len: 10,
+            encoding: Default::default(),
+        })
+        .await;
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        if let IronhiveRespond::FileRange { data, eof, .. } = resp {
+            assert_eq!(data, "3");
+            assert!(eof);
+        } else {
+            panic!("unexpected resp: {resp:?}");
+        }
+
+        // Outside of the allow-list.
+        let resp = request(IronhiveRequest::ListDir {
+            path: dir.clone(),
+            offset: 0,
+            limit: 10,
+        })
+        .await;
+        assert!(resp.headers.is_some());
+        let err = serde_json::from_slice::<IronhiveError>(&resp.payload).unwrap();
+        assert_eq!(err.code, ErrorCode::PermissionDenied);
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
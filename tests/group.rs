@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use ironhive_config::generate_agent_id;
+use ironhive_core::{Agent, ErrorCode, Ironhive, IronhiveRequest, IronhiveRespond};
+use ironhive_shared::client::{request_group, GroupTarget};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn group() {
+    let server = nats_server::run_basic_server();
+
+    let agent_ids = [generate_agent_id(), generate_agent_id()];
+
+    info!("agent ids: {agent_ids:?}");
+
+    let mut first = Agent::new(agent_ids[0].to_string(), &server.client_url()).unwrap();
+    first.groups = vec!["site-a".into(), "role.db".into()];
+    let mut second = Agent::new(agent_ids[1].to_string(), &server.client_url()).unwrap();
+    second.groups = vec!["site-a".into()];
+
+    let first = Ironhive::new(first).await.unwrap();
+    let second = Ironhive::new(second).await.unwrap();
+
+    let client = first.client.clone();
+
+    let tasks = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let mut target = GroupTarget::new("site-a");
+        target.expected = Some(2);
+        let replies = request_group(&client, &target, &IronhiveRequest::Ping)
+            .await
+            .unwrap();
+        debug!("{replies:#?}");
+        assert_eq!(replies.len(), 2);
+        for id in &agent_ids {
+            let reply = replies.iter().find(|reply| &reply.agent_id == id).unwrap();
+            assert_eq!(reply.result, Ok(IronhiveRespond::Pong));
+        }
+
+        // Errors come back per agent.
+        let mut target = GroupTarget::new("role.db");
+        target.timeout = Duration::from_secs(1);
+        let replies = request_group(&client, &target, &IronhiveRequest::CancelJob { id: 999 })
+            .await
+            .unwrap();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].agent_id, agent_ids[0]);
+        assert_eq!(
+            replies[0].result.as_ref().unwrap_err().code,
+            ErrorCode::NotFound
+        );
+
+        let replies = request_group(&client, &GroupTarget::new("nobody"), &IronhiveRequest::Ping)
+            .await
+            .unwrap();
+        assert!(replies.is_empty());
+    };
+
+    tokio::select! {
+        res = first.run() => res.unwrap(),
+        res = second.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
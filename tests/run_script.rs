@@ -37,6 +37,13 @@ async fn run_script() {
                     timeout: Duration::from_secs(3),
                     env_vars: HashMap::new(),
                     id: 1,
+                    stream_subject: None,
+                    run_as_user: None,
+                    detached: false,
+                    stdin: None,
+                    cwd: None,
+                    max_output_bytes: None,
+                    encoding: Default::default(),
                 }
                 .as_bytes(),
             )
@@ -74,6 +81,13 @@ print(f"The value of the {n}th term in the Fibonacci sequence is: {result}")
                     timeout: Duration::from_secs(10),
                     env_vars: HashMap::new(),
                     id: 2,
+                    stream_subject: None,
+                    run_as_user: None,
+                    detached: false,
+                    stdin: None,
+                    cwd: None,
+                    max_output_bytes: None,
+                    encoding: Default::default(),
                 }
                 .as_bytes(),
             )
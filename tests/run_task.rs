@@ -0,0 +1,123 @@
+use std::{collections::HashMap, time::Duration};
+
+use futures_util::StreamExt;
+use ironhive_config::generate_agent_id;
+use ironhive_core::{
+    Agent, AutomatedTask, Ironhive, IronhiveRequest, IronhiveRespond, ScriptMode, TaskAction,
+};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+fn python_action(code: &str) -> TaskAction {
+    TaskAction::CmdScript {
+        code: code.into(),
+        mode: ScriptMode::Binary {
+            path: "python3".into(),
+            ext: ".py".into(),
+        },
+        args: vec![],
+        env_vars: HashMap::new(),
+        detached: false,
+        timeout: Duration::from_secs(10),
+    }
+}
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn run_task() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    let req = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let tasks = [
+            AutomatedTask {
+                id: 1,
+                task_actions: vec![
+                    python_action(r#"print("first")"#),
+                    python_action("import sys; sys.exit(3)"),
+                    python_action(r#"print("third")"#),
+                ],
+                enabled: true,
+                continue_on_error: true,
+            },
+            AutomatedTask {
+                id: 2,
+                task_actions: vec![
+                    python_action("import sys; sys.exit(3)"),
+                    python_action(r#"print("unreachable")"#),
+                ],
+                enabled: true,
+                continue_on_error: false,
+            },
+            AutomatedTask {
+                id: 3,
+                task_actions: vec![python_action(r#"print("disabled")"#)],
+                enabled: false,
+                continue_on_error: false,
+            },
+        ];
+
+        for task in tasks {
+            client
+                .publish_with_reply(
+                    agent_id.to_string(),
+                    "ironhive".into(),
+                    IronhiveRequest::RunTask { task }.as_bytes(),
+                )
+                .await
+                .unwrap();
+        }
+    });
+
+    let client = rpc.client.clone();
+
+    let res = async {
+        let mut subscriber = client.subscribe("ironhive".into()).await.unwrap();
+
+        for _ in 0..3 {
+            let raw_resp = subscriber.next().await.unwrap();
+            let resp = serde_json::from_slice::<IronhiveRespond>(&raw_resp.payload).unwrap();
+            debug!("{resp:#?}");
+
+            if let IronhiveRespond::RunTaskResp { id, results } = resp {
+                let outputs = results
+                    .iter()
+                    .map(|r| (r.stdout.trim(), r.retcode))
+                    .collect::<Vec<_>>();
+                match id {
+                    1 => assert_eq!(
+                        outputs,
+                        vec![("first", Some(0)), ("", Some(3)), ("third", Some(0))]
+                    ),
+                    2 => assert_eq!(outputs, vec![("", Some(3))]),
+                    3 => assert!(outputs.is_empty()),
+                    _ => panic!("Unknow resp"),
+                }
+            } else {
+                panic!("unexpected resp: {resp:?}");
+            }
+        }
+    };
+
+    let tasks = async {
+        let _ = tokio::join!(req, res);
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
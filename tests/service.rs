@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use ironhive_config::generate_agent_id;
+use ironhive_core::{
+    Agent, ErrorCode, Ironhive, IronhiveError, IronhiveRequest, IronhiveRespond, SERVICE_NAME,
+};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn service() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    let tasks = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let request = |subject: String, payload| {
+            let client = client.clone();
+            async move { client.request(subject, payload).await.unwrap() }
+        };
+
+        // Discovered like any other NATS micro-service.
+        let pong = request(format!("$SRV.PING.{SERVICE_NAME}"), Default::default()).await;
+        let pong = serde_json::from_slice::<serde_json::Value>(&pong.payload).unwrap();
+        debug!("{pong:#?}");
+        assert_eq!(pong["name"], SERVICE_NAME);
+        assert_eq!(pong["metadata"]["agent_id"], agent_id.to_string());
+        let id = pong["id"].as_str().unwrap().to_string();
+
+        let info = request(format!("$SRV.INFO.{SERVICE_NAME}.{id}"), Default::default()).await;
+        let info = serde_json::from_slice::<serde_json::Value>(&info.payload).unwrap();
+        let subjects = info["endpoints"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|endpoint| endpoint["subject"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert!(subjects.contains(&agent_id.to_string()));
+        assert!(subjects.contains(&format!("{agent_id}.ping")));
+
+        // The agent id subject takes any function.
+        let resp = request(agent_id.to_string(), IronhiveRequest::Ping.as_bytes()).await;
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        assert_eq!(resp, IronhiveRespond::Pong);
+
+        let resp = request(format!("{agent_id}.ping"), IronhiveRequest::Ping.as_bytes()).await;
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        assert_eq!(resp, IronhiveRespond::Pong);
+
+        // A function endpoint only takes its own function.
+        let resp = request(
+            format!("{agent_id}.ping"),
+            IronhiveRequest::Procs.as_bytes(),
+        )
+        .await;
+        assert!(resp.headers.is_some());
+        let err = serde_json::from_slice::<IronhiveError>(&resp.payload).unwrap();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+
+        let stats = request(
+            format!("$SRV.STATS.{SERVICE_NAME}.{id}"),
+            Default::default(),
+        )
+        .await;
+        let stats = serde_json::from_slice::<serde_json::Value>(&stats.payload).unwrap();
+        debug!("{stats:#?}");
+        let ping = stats["endpoints"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|endpoint| endpoint["name"] == "ping")
+            .unwrap();
+        assert_eq!(ping["num_requests"], 1);
+        assert_eq!(ping["data"]["num_errors"], 1);
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn subject_prefix() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let mut agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+    agent.subject_prefix = Some("acme.ironhive".into());
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    let tasks = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let request = |func: &str, payload: &'static str| {
+            let client = client.clone();
+            let subject = format!("acme.ironhive.{agent_id}.{func}");
+            async move { client.request(subject, payload.into()).await.unwrap() }
+        };
+
+        // The function comes from the subject.
+        let resp = request("procs", "").await;
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        assert!(matches!(resp, IronhiveRespond::ProcessMsg { .. }));
+
+        let resp = request("canceljob", r#"{"id":999}"#).await;
+        let err = serde_json::from_slice::<IronhiveError>(&resp.payload).unwrap();
+        debug!("{err:?}");
+        assert_eq!(err.code, ErrorCode::NotFound);
+
+        let resp = request("ping", r#"{"func":"rebootnow"}"#).await;
+        let err = serde_json::from_slice::<IronhiveError>(&resp.payload).unwrap();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+
+        // Nothing listens on the bare agent id anymore.
+        let resp = client
+            .request(agent_id.to_string(), IronhiveRequest::Ping.as_bytes())
+            .await;
+        assert!(resp.is_err());
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
@@ -0,0 +1,123 @@
+#![cfg(unix)]
+
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use ironhive_config::generate_agent_id;
+use ironhive_core::{
+    Agent, Ironhive, IronhiveRequest, IronhiveRespond, ShellCloseReason, ShellClosedNats,
+};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn shell_session() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    let request = |req: IronhiveRequest| {
+        let client = client.clone();
+        let agent_id = agent_id.to_string();
+        async move {
+            let resp = client.request(agent_id, req.as_bytes()).await.unwrap();
+            serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap()
+        }
+    };
+
+    let tasks = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let resp = request(IronhiveRequest::OpenShell {
+            shell: Some("/bin/sh".into()),
+            cols: 80,
+            rows: 24,
+            idle_timeout: Duration::from_secs(60),
+        })
+        .await;
+        debug!("{resp:#?}");
+        let IronhiveRespond::ShellOpened {
+            session_id,
+            input_subject,
+            output_subject,
+            closed_subject,
+            ..
+        } = resp
+        else {
+            panic!("unexpected resp: {resp:?}");
+        };
+
+        // Give the shell time to print its prompt before anyone listens.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let mut output = client.subscribe(output_subject).await.unwrap();
+        let mut closed = client.subscribe(closed_subject).await.unwrap();
+
+        // Ready, the agent relays the prompt that waited for us.
+        client
+            .publish(input_subject.clone(), Default::default())
+            .await
+            .unwrap();
+        let mut received = String::new();
+        while !received.trim_end().ends_with(['$', '#']) {
+            let msg = tokio::time::timeout(Duration::from_secs(5), output.next())
+                .await
+                .unwrap()
+                .unwrap();
+            received.push_str(&String::from_utf8_lossy(&msg.payload));
+        }
+
+        let resp = request(IronhiveRequest::ResizeShell {
+            session_id: session_id.clone(),
+            cols: 120,
+            rows: 30,
+        })
+        .await;
+        assert_eq!(resp, IronhiveRespond::Ok);
+
+        client
+            .publish(input_subject, "stty size\n".into())
+            .await
+            .unwrap();
+
+        received.clear();
+        while !received.contains("30 120") {
+            let msg = tokio::time::timeout(Duration::from_secs(5), output.next())
+                .await
+                .unwrap()
+                .unwrap();
+            received.push_str(&String::from_utf8_lossy(&msg.payload));
+        }
+
+        let resp = request(IronhiveRequest::CloseShell {
+            session_id: session_id.clone(),
+        })
+        .await;
+        assert_eq!(resp, IronhiveRespond::Ok);
+
+        let msg = tokio::time::timeout(Duration::from_secs(5), closed.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let msg = serde_json::from_slice::<ShellClosedNats>(&msg.payload).unwrap();
+        debug!("{msg:#?}");
+        assert_eq!(msg.session_id, session_id);
+        assert_eq!(msg.reason, ShellCloseReason::Closed);
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
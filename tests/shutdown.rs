@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use ironhive_config::generate_agent_id;
+use ironhive_core::{Agent, Ironhive, IronhiveRequest, IronhiveRespond};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn shutdown() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let mut agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+    agent.shutdown_timeout = Duration::from_secs(1);
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+    let shutdown = rpc.shutdown_handle();
+
+    let mut offline = client.subscribe(agent_id.to_string()).await.unwrap();
+
+    let run = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        client
+            .request(
+                agent_id.to_string(),
+                IronhiveRequest::RawCmd {
+                    shell: "sh".into(),
+                    command: "echo started; sleep 60".into(),
+                    timeout: Duration::from_secs(120),
+                    stream_subject: None,
+                    run_as_user: None,
+                    detached: false,
+                    stdin: None,
+                    cwd: None,
+                    max_output_bytes: None,
+                    encoding: Default::default(),
+                    structured: false,
+                }
+                .as_bytes(),
+            )
+            .await
+            .unwrap()
+    };
+
+    let stop = async {
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        shutdown.shutdown();
+    };
+
+    let (res, (resp, _)) = tokio::join!(rpc.run(), async { tokio::join!(run, stop) });
+    res.unwrap();
+
+    // The job was cancelled after the shutdown timeout but still replied.
+    let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+    debug!("{resp:#?}");
+    assert!(matches!(resp, IronhiveRespond::RawCMDResp { .. }));
+
+    let offline = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let msg = offline.next().await.unwrap();
+            if msg.reply.as_deref() == Some("agent-offline") {
+                break msg;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    let offline = serde_json::from_slice::<serde_json::Value>(&offline.payload).unwrap();
+    assert_eq!(offline["agent_id"], agent_id.to_string());
+
+    // Nobody takes requests anymore.
+    let resp = client
+        .request(agent_id.to_string(), IronhiveRequest::Ping.as_bytes())
+        .await;
+    assert!(resp.is_err());
+}
@@ -0,0 +1,112 @@
+use std::{collections::HashMap, time::Duration};
+
+use futures_util::StreamExt;
+use ironhive_config::generate_agent_id;
+use ironhive_core::{
+    Agent, Ironhive, IronhiveRequest, IronhiveRespond, OutputStream, ScriptMode, StreamChunkNats,
+};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn stream_output() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    let req = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        client
+            .publish_with_reply(
+                agent_id.to_string(),
+                "ironhive".into(),
+                IronhiveRequest::RunScript {
+                    code: r#"
+import sys, time
+for i in range(3):
+    print(f"line {i}", flush=True)
+    time.sleep(0.2)
+print("oops", file=sys.stderr, flush=True)
+"#
+                    .into(),
+                    mode: ScriptMode::Binary {
+                        path: "python3".into(),
+                        ext: ".py".into(),
+                    },
+                    script_args: vec![],
+                    timeout: Duration::from_secs(10),
+                    env_vars: HashMap::new(),
+                    id: 1,
+                    stream_subject: Some("ironhive.stream".into()),
+                    run_as_user: None,
+                    detached: false,
+                    stdin: None,
+                    cwd: None,
+                    max_output_bytes: None,
+                    encoding: Default::default(),
+                }
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+    });
+
+    let client = rpc.client.clone();
+
+    let res = async {
+        let mut chunks = client.subscribe("ironhive.stream".into()).await.unwrap();
+        let mut subscriber = client.subscribe("ironhive".into()).await.unwrap();
+
+        let raw_resp = subscriber.next().await.unwrap();
+        let resp = serde_json::from_slice::<IronhiveRespond>(&raw_resp.payload).unwrap();
+        debug!("{resp:#?}");
+
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        let mut expected_seq = 0;
+        while let Ok(Some(raw_chunk)) =
+            tokio::time::timeout(Duration::from_millis(500), chunks.next()).await
+        {
+            let chunk = serde_json::from_slice::<StreamChunkNats>(&raw_chunk.payload).unwrap();
+            assert_eq!(chunk.seq, expected_seq);
+            expected_seq += 1;
+            match chunk.stream {
+                OutputStream::Stdout => stdout.push_str(&chunk.data),
+                OutputStream::Stderr => stderr.push_str(&chunk.data),
+            }
+        }
+
+        if let IronhiveRespond::RunScriptResp {
+            stdout: final_stdout,
+            stderr: final_stderr,
+            ..
+        } = resp
+        {
+            assert_eq!(stdout, final_stdout);
+            assert_eq!(stderr, final_stderr);
+            assert_eq!(stderr.trim(), "oops");
+        } else {
+            panic!("unexpected resp: {resp:?}");
+        }
+    };
+
+    let tasks = async {
+        let _ = tokio::join!(req, res);
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
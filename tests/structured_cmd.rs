@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use ironhive_config::generate_agent_id;
+use ironhive_core::{Agent, Ironhive, IronhiveRequest, IronhiveRespond, Termination};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn structured_command() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    let tasks = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let resp = client
+            .request(
+                agent_id.to_string(),
+                IronhiveRequest::RawCmd {
+                    shell: "sh".into(),
+                    command: "echo out; echo err >&2; exit 3".into(),
+                    timeout: Duration::from_secs(10),
+                    run_as_user: None,
+                    stream_subject: None,
+                    detached: false,
+                    stdin: None,
+                    cwd: None,
+                    max_output_bytes: None,
+                    encoding: Default::default(),
+                    structured: true,
+                }
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        debug!("{resp:#?}");
+
+        if let IronhiveRespond::RawCMDOutput {
+            stdout,
+            stderr,
+            retcode,
+            signal,
+            termination,
+            timed_out,
+            ..
+        } = resp
+        {
+            assert_eq!(stdout, "out\n");
+            assert_eq!(stderr, "err\n");
+            assert_eq!(retcode, 3);
+            assert_eq!(signal, None);
+            assert_eq!(termination, Termination::Exited);
+            assert!(!timed_out);
+        } else {
+            panic!("unexpected resp: {resp:?}");
+        }
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use ironhive_config::generate_agent_id;
+use ironhive_core::{Agent, Ironhive, IronhiveRequest, IronhiveRespond};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn command_timeout() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    let tasks = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let now = std::time::Instant::now();
+        let resp = client
+            .request(
+                agent_id.to_string(),
+                IronhiveRequest::RawCmd {
+                    shell: "sh".into(),
+                    // The background sleep keeps the pipes open unless the whole tree is killed.
+                    command: "echo started; sleep 60 & sleep 60".into(),
+                    timeout: Duration::from_secs(1),
+                    stream_subject: None,
+                    run_as_user: None,
+                    detached: false,
+                    stdin: None,
+                    cwd: None,
+                    max_output_bytes: None,
+                    encoding: Default::default(),
+                    structured: false,
+                }
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        debug!("{resp:#?}");
+
+        assert!(now.elapsed() < Duration::from_secs(10));
+        if let IronhiveRespond::RawCMDResp {
+            results, timed_out, ..
+        } = resp
+        {
+            assert_eq!(results.trim(), "started");
+            assert!(timed_out);
+        } else {
+            panic!("unexpected resp: {resp:?}");
+        }
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use ironhive_config::generate_agent_id;
+use ironhive_core::{Agent, ErrorCode, Ironhive, IronhiveError, IronhiveRequest};
+use ironhive_shared::client::{forward_tunnel, TunnelTarget};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::info;
+use tracing_test::traced_test;
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn tunnel() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let mut agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+    agent.subject_prefix = Some("acme.ironhive".into());
+    let agent_subject = agent.subject();
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    // A service only listening on the agent's localhost, greeting first like SSH does.
+    let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_port = echo.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, _) = echo.accept().await.unwrap();
+        stream.write_all(b"hi\n").await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+
+    let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = local.local_addr().unwrap();
+    let mut target = TunnelTarget::new(agent_id.to_string(), "127.0.0.1", echo_port);
+    target.subject_prefix = Some("acme.ironhive".into());
+    assert_eq!(target.subject(), agent_subject);
+    tokio::spawn(forward_tunnel(client.clone(), local, target));
+
+    let tasks = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let mut stream = TcpStream::connect(local_addr).await.unwrap();
+        stream.write_all(b"hello through nats").await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut received = vec![];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, b"hi\nhello through nats");
+
+        // Only localhost is allowed by default.
+        let resp = client
+            .request(
+                agent_subject.clone(),
+                IronhiveRequest::OpenTunnel {
+                    target_host: "192.0.2.1".into(),
+                    target_port: 22,
+                    idle_timeout: Duration::from_secs(60),
+                }
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let err = serde_json::from_slice::<IronhiveError>(&resp.payload).unwrap();
+        assert_eq!(err.code, ErrorCode::PermissionDenied);
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
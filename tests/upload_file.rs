@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use ironhive_config::generate_agent_id;
+use ironhive_core::{Agent, ErrorCode, Ironhive, IronhiveError, IronhiveRequest, IronhiveRespond};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+// sha256 of "hello chunked upload".
+const SHA256: &str = "8df4f35a9f868b53e4e3b61d06dc3fad4b786d8d048614f950afd3ed5b889180";
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn upload_file() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    let dir = std::env::temp_dir().join(format!("ironhive-upload-{agent_id}"));
+    let path = dir.join("hello.txt");
+
+    let request = |req: IronhiveRequest| {
+        let client = client.clone();
+        let agent_id = agent_id.to_string();
+        async move { client.request(agent_id, req.as_bytes()).await.unwrap() }
+    };
+
+    let start = || IronhiveRequest::UploadStart {
+        upload_id: "hello".into(),
+        path: path.clone(),
+        size: 20,
+        sha256: SHA256.into(),
+        mode: None,
+        owner: None,
+    };
+
+    let tasks = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let resp = request(start()).await;
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        debug!("{resp:#?}");
+        assert!(matches!(
+            resp,
+            IronhiveRespond::UploadStatus { received: 0, .. }
+        ));
+
+        let resp = request(IronhiveRequest::UploadChunk {
+            upload_id: "hello".into(),
+            offset: 0,
+            data: "aGVsbG8gY2g=".into(),
+        })
+        .await;
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        assert!(matches!(
+            resp,
+            IronhiveRespond::UploadStatus { received: 8, .. }
+        ));
+
+        // The chunk at offset 8 got lost, the agent refuses to skip it.
+        let resp = request(IronhiveRequest::UploadChunk {
+            upload_id: "hello".into(),
+            offset: 14,
+            data: "dXBsb2Fk".into(),
+        })
+        .await;
+        assert!(resp.headers.is_some());
+        let err = serde_json::from_slice::<IronhiveError>(&resp.payload).unwrap();
+        assert_eq!(err.code, ErrorCode::Conflict);
+        assert!(err.retryable);
+        assert_eq!(err.details.unwrap()["expected"], 8);
+
+        // Starting again tells where to resume.
+        let resp = request(start()).await;
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        assert!(matches!(
+            resp,
+            IronhiveRespond::UploadStatus { received: 8, .. }
+        ));
+
+        let resp = request(IronhiveRequest::UploadChunk {
+            upload_id: "hello".into(),
+            offset: 8,
+            data: "dW5rZWQgdXBsb2Fk".into(),
+        })
+        .await;
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        assert!(matches!(
+            resp,
+            IronhiveRespond::UploadStatus { received: 20, .. }
+        ));
+
+        let resp = request(IronhiveRequest::UploadFinish {
+            upload_id: "hello".into(),
+        })
+        .await;
+        let resp = serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap();
+        debug!("{resp:#?}");
+        if let IronhiveRespond::UploadDone { path: done, size } = resp {
+            assert_eq!(done, path);
+            assert_eq!(size, 20);
+        } else {
+            panic!("unexpected resp: {resp:?}");
+        }
+
+        assert_eq!(
+            tokio::fs::read_to_string(&path).await.unwrap(),
+            "hello chunked upload"
+        );
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use ironhive_config::generate_agent_id;
+use ironhive_core::{Agent, Ironhive, IronhiveRequest, IronhiveRespond};
+use tracing::{debug, info};
+use tracing_test::traced_test;
+
+#[traced_test]
+#[tokio::test(flavor = "multi_thread")]
+async fn write_file() {
+    let server = nats_server::run_basic_server();
+
+    let agent_id = generate_agent_id();
+
+    info!("agent id: {}", agent_id.to_string());
+
+    let agent = Agent::new(agent_id.to_string(), &server.client_url()).unwrap();
+
+    let rpc = Ironhive::new(agent).await.unwrap();
+
+    let client = rpc.client.clone();
+
+    let dir = std::env::temp_dir().join(format!("ironhive-write-{agent_id}"));
+    tokio::fs::create_dir_all(&dir).await.unwrap();
+    let path = dir.join("app.conf");
+    tokio::fs::write(&path, "port = 80\n").await.unwrap();
+
+    let request = |req: IronhiveRequest| {
+        let client = client.clone();
+        let agent_id = agent_id.to_string();
+        async move {
+            let resp = client.request(agent_id, req.as_bytes()).await.unwrap();
+            serde_json::from_slice::<IronhiveRespond>(&resp.payload).unwrap()
+        }
+    };
+
+    let tasks = async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let resp = request(IronhiveRequest::WriteFile {
+            path: path.clone(),
+            content: "port = 8080\n".into(),
+            encoding: Default::default(),
+            mode: None,
+            backup: true,
+        })
+        .await;
+        debug!("{resp:#?}");
+        let IronhiveRespond::FileWritten {
+            before_sha256,
+            after_sha256,
+            backup_path: Some(backup_path),
+            ..
+        } = resp
+        else {
+            panic!("unexpected resp: {resp:?}");
+        };
+        assert!(before_sha256.is_some());
+        assert_ne!(before_sha256, Some(after_sha256.clone()));
+        assert_eq!(
+            tokio::fs::read_to_string(&path).await.unwrap(),
+            "port = 8080\n"
+        );
+
+        let resp = request(IronhiveRequest::RestoreBackup {
+            path: path.clone(),
+            backup_path: None,
+        })
+        .await;
+        debug!("{resp:#?}");
+        if let IronhiveRespond::BackupRestored {
+            backup_path: restored,
+            before_sha256: restored_before,
+            after_sha256: restored_after,
+            ..
+        } = resp
+        {
+            assert_eq!(restored, backup_path);
+            assert_eq!(restored_before, Some(after_sha256));
+            assert_eq!(Some(restored_after), before_sha256);
+        } else {
+            panic!("unexpected resp: {resp:?}");
+        }
+        assert_eq!(
+            tokio::fs::read_to_string(&path).await.unwrap(),
+            "port = 80\n"
+        );
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    };
+
+    tokio::select! {
+        res = rpc.run() => res.unwrap(),
+        _ = tasks => {
+            info!("well");
+        },
+    }
+}
@@ -78,7 +78,24 @@ fn main() {
             windows::svc::test_mgr,
             utils::test_public_ip,
             windows::svc::test_installed_software_list,
-            agent::tests::test_agent
+            agent::tests::test_agent,
+            sched::tests::test_persist_sched_tasks,
+            sched::tests::test_next_run,
+            sched::tests::test_drain_runs,
+            jobs::tests::test_jobs,
+            utils::test_decode,
+            cmd::tests::test_capped_buffer,
+            transfer::tests::test_upload,
+            transfer::tests::test_download,
+            agent::tests::test_file_requests,
+            agent::tests::test_write_file,
+            agent::tests::test_capabilities,
+            agent::tests::test_tunnel_targets,
+            error::tests::test_error_payload,
+            service::tests::test_error_stats,
+            service::tests::test_parse_request,
+            limits::tests::test_limiter,
+            shutdown::tests::test_shutdown_handle
         );
         #[cfg(windows)]
         cmd!(